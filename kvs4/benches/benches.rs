@@ -1,7 +1,15 @@
-use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
-use kvs::{KvStore, KvsEngine, SledStore};
+use criterion::measurement::WallTime;
+use criterion::{
+    criterion_group, criterion_main, BatchSize, Bencher, BenchmarkGroup, Criterion, Throughput,
+};
+use kvs::{
+    KvStore, KvsClient, KvsEngine, KvsServer, MemStore, RayonThreadPool, SharedQueueThreadPool,
+    SledStore, ThreadPool, WorkStealingThreadPool,
+};
 use rand::prelude::*;
 use sled;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 
 fn set_bench(c: &mut Criterion) {
@@ -73,5 +81,93 @@ fn get_bench(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, set_bench, get_bench);
+fn open_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("open_bench");
+    for gens in &vec![1, 8, 32] {
+        let template = generations(*gens);
+        // without a snapshot every generation is parsed
+        std::fs::remove_file(template.path().join("index.snapshot")).unwrap();
+        group.bench_with_input(format!("kvs_{}", gens), &template, open_copy);
+    }
+    let template = generations(32);
+    group.bench_with_input("kvs_snapshot", &template, open_copy);
+    group.finish();
+}
+
+/// Writes the same number of records in every case, spread over `gens` generations.
+fn generations(gens: u64) -> TempDir {
+    let temp_dir = TempDir::new().unwrap();
+    for gen in 0..gens {
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        for key_i in 0..(1 << 16) / gens {
+            store
+                .set(format!("key{}_{}", gen, key_i), "value".to_string())
+                .unwrap();
+        }
+    }
+    temp_dir
+}
+
+/// Measures opening a fresh copy of `template`, since every open starts a new
+/// generation and saves a snapshot when the store is dropped.
+fn open_copy(b: &mut Bencher, template: &TempDir) {
+    b.iter_batched(
+        || {
+            let temp_dir = TempDir::new().unwrap();
+            for entry in std::fs::read_dir(template.path()).unwrap() {
+                let path = entry.unwrap().path();
+                std::fs::copy(&path, temp_dir.path().join(path.file_name().unwrap())).unwrap();
+            }
+            temp_dir
+        },
+        |temp_dir| (KvStore::open(temp_dir.path()).unwrap(), temp_dir),
+        BatchSize::PerIteration,
+    )
+}
+
+/// Clients connected at once in `server_bench`, more than the workers of the pools.
+const CLIENTS: u64 = 16;
+/// Sets and gets sent by every client in `server_bench`.
+const REQUESTS: u64 = 50;
+
+fn server_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("server_bench");
+    group.throughput(Throughput::Elements(CLIENTS * REQUESTS * 2));
+    bench_pool::<SharedQueueThreadPool>(&mut group, "shared_queue");
+    bench_pool::<RayonThreadPool>(&mut group, "rayon");
+    bench_pool::<WorkStealingThreadPool>(&mut group, "work_stealing");
+    group.finish();
+}
+
+/// Measures requests served by a server on a pool of type `P`, each client on a
+/// connection of its own.
+fn bench_pool<P: ThreadPool>(group: &mut BenchmarkGroup<WallTime>, name: &str) {
+    let pool = P::new(4).unwrap();
+    let handle = KvsServer::new(MemStore::new(), pool)
+        .start("127.0.0.1:0".parse().unwrap())
+        .unwrap();
+    let addr = handle.local_addr();
+    group.bench_function(name, |b| {
+        b.iter(|| {
+            let clients: Vec<_> = (0..CLIENTS)
+                .map(|i| {
+                    thread::spawn(move || {
+                        let mut client = KvsClient::connect(addr).unwrap();
+                        for j in 0..REQUESTS {
+                            let key = format!("key{}_{}", i, j);
+                            client.set(key.clone(), "value".to_owned()).unwrap();
+                            client.get(key).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for client in clients {
+                client.join().unwrap();
+            }
+        })
+    });
+    handle.shutdown(Duration::from_secs(1)).unwrap();
+}
+
+criterion_group!(benches, set_bench, get_bench, open_bench, server_bench);
 criterion_main!(benches);
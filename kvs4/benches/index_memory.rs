@@ -0,0 +1,53 @@
+//! Compares the memory the index of a `KvStore` takes with and without
+//! `compact_index`. Criterion only measures time, so this counts the bytes that
+//! stay allocated after `open`.
+
+use kvs::{KvStore, KvStoreOptions};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tempfile::TempDir;
+
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn main() {
+    for &keys in &[1 << 16, 1 << 20] {
+        let temp_dir = TempDir::new().unwrap();
+        let entries = (0..keys).map(|i| Ok((format!("key{:016}", i), "value".to_string())));
+        drop(KvStore::bulk_load(temp_dir.path(), entries).unwrap());
+
+        for &compact_index in &[false, true] {
+            let options = KvStoreOptions {
+                compact_index,
+                ..KvStoreOptions::default()
+            };
+            let before = ALLOCATED.load(Ordering::SeqCst);
+            let store = KvStore::open_with(temp_dir.path(), options).unwrap();
+            let used = ALLOCATED.load(Ordering::SeqCst) - before;
+            println!(
+                "index_memory/{}_{}: {} bytes, {:.1} bytes per key",
+                if compact_index { "compact" } else { "full" },
+                keys,
+                used,
+                used as f64 / keys as f64
+            );
+            drop(store);
+        }
+    }
+}
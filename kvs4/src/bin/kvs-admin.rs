@@ -0,0 +1,247 @@
+use clap::{arg_enum, AppSettings};
+use kvs::{
+    finish_swap, migrate, swap_dirs, Digest, DirLock, DumpSource, KvStore, KvStoreFollower,
+    LsmStore, Result, SledStore,
+};
+use log::{error, info, warn, LevelFilter};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use structopt::StructOpt;
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Engine {
+        kvs,
+        sled,
+        lsm
+    }
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-admin",
+    raw(global_settings = "&[\
+                           AppSettings::DisableHelpSubcommand,\
+                           AppSettings::VersionlessSubcommands
+
+]")
+)]
+struct Opt {
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(
+        name = "verify",
+        about = "Check every generation of a kvs data directory without modifying it"
+    )]
+    Verify {
+        #[structopt(name = "DIR", help = "The data directory", parse(from_os_str))]
+        dir: PathBuf,
+    },
+    #[structopt(
+        name = "repair",
+        about = "Truncate corrupt logs, quarantine unreadable files and compact the rest"
+    )]
+    Repair {
+        #[structopt(name = "DIR", help = "The data directory", parse(from_os_str))]
+        dir: PathBuf,
+    },
+    #[structopt(
+        name = "migrate",
+        about = "Copy all data into a directory of another engine and swap it in place"
+    )]
+    Migrate {
+        #[structopt(
+            long,
+            help = "The engine the data is currently stored in",
+            value_name = "ENGINE-NAME",
+            raw(possible_values = "&Engine::variants()")
+        )]
+        from: Engine,
+        #[structopt(
+            long,
+            help = "The engine to migrate the data to",
+            value_name = "ENGINE-NAME",
+            raw(possible_values = "&Engine::variants()")
+        )]
+        to: Engine,
+        #[structopt(name = "DIR", help = "The data directory", parse(from_os_str))]
+        dir: PathBuf,
+    },
+}
+
+fn main() {
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+    let opt = Opt::from_args();
+    match run(opt) {
+        Ok(true) => {}
+        Ok(false) => exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+/// Returns whether the directory is healthy after the command.
+fn run(opt: Opt) -> Result<bool> {
+    match opt.command {
+        Command::Verify { dir } => {
+            check_engine(&dir)?;
+            let report = KvStore::verify(&dir)?;
+            println!("format: {:?}", report.format);
+            for gen in &report.generations {
+                print!(
+                    "{}.log: {} records ({} sets, {} removes), {} bytes",
+                    gen.gen,
+                    gen.records(),
+                    gen.sets,
+                    gen.removes,
+                    gen.len
+                );
+                match (gen.corrupt_offset, &gen.error) {
+                    (Some(offset), Some(e)) => println!(", corrupt at offset {}: {}", offset, e),
+                    _ => println!(),
+                }
+            }
+            for orphan in &report.orphan_compactions {
+                println!("orphan compaction file: {}", orphan.display());
+            }
+            println!(
+                "index: {} live keys, {} bytes uncompacted",
+                report.live_keys, report.uncompacted
+            );
+            Ok(report.is_clean())
+        }
+        Command::Repair { dir } => {
+            check_engine(&dir)?;
+            let report = KvStore::repair(&dir)?;
+            for (gen, len) in &report.truncated {
+                println!("truncated {}.log to {} bytes", gen, len);
+            }
+            for gen in &report.quarantined {
+                println!("quarantined {}.log", gen);
+            }
+            for orphan in &report.orphans_removed {
+                println!("quarantined orphan {}", orphan.display());
+            }
+            println!("compacted live data into {}.log", report.compaction_gen);
+            Ok(true)
+        }
+        Command::Migrate { from, to, dir } => {
+            // The swap marker names the canonical path. `dir` itself is missing if a
+            // migration crashed between its two renames, so only its parent is
+            // resolved.
+            let dir = match (dir.parent(), dir.file_name()) {
+                (Some(parent), Some(name)) if !dir.exists() => {
+                    let parent = if parent.as_os_str().is_empty() {
+                        Path::new(".")
+                    } else {
+                        parent
+                    };
+                    parent.canonicalize()?.join(name)
+                }
+                _ => dir.canonicalize()?,
+            };
+            if finish_swap(&dir)? {
+                println!("finished the interrupted migration of {}", dir.display());
+                return Ok(true);
+            }
+            // keeps a server from opening the data while it is copied and swapped
+            let _lock = DirLock::acquire(&dir)?;
+            if let Some(engine) = current_engine(&dir)? {
+                if engine != from {
+                    error!("Wrong engine!");
+                    exit(1);
+                }
+            }
+            let staging = sibling(&dir, &format!("migrating-{}", to));
+            let backup = sibling(&dir, &format!("{}-backup", from));
+            if staging.exists() || backup.exists() {
+                error!(
+                    "{} or {} already exists, remove it first",
+                    staging.display(),
+                    backup.display()
+                );
+                exit(1);
+            }
+
+            // Both engines are closed at the end of this statement, before the swap.
+            // sled and lsm have no read-only mode. Opening lsm recovers it like a
+            // server start would: it cuts off a torn tail of the write-ahead log and
+            // removes leftover temporary and replaced tables. None of that is live
+            // data, so the backup still holds everything that was migrated.
+            let res = match from {
+                Engine::kvs => migrate_to(&KvStoreFollower::open(&dir)?, to, &staging),
+                Engine::sled => migrate_to(&SledStore::new(sled::open(&dir)?), to, &staging),
+                Engine::lsm => migrate_to(&LsmStore::open(&dir)?, to, &staging),
+            };
+            let digest = match res {
+                Ok(digest) => digest,
+                Err(e) => {
+                    if let Err(e) = std::fs::remove_dir_all(&staging) {
+                        warn!("{:?} cannot be deleted: {}", staging, e);
+                    }
+                    return Err(e);
+                }
+            };
+            info!("Copied and verified {}", digest);
+
+            std::fs::write(staging.join("engine"), format!("{}", to))?;
+            swap_dirs(&dir, &staging, &backup)?;
+            println!(
+                "migrated {} from {} to {}, old data kept in {}",
+                dir.display(),
+                from,
+                to,
+                backup.display()
+            );
+            Ok(true)
+        }
+    }
+}
+
+/// Copies everything in `source` into a new `to` engine at `staging`.
+fn migrate_to<S: DumpSource>(source: &S, to: Engine, staging: &Path) -> Result<Digest> {
+    match to {
+        Engine::kvs => migrate(source, &KvStore::open(staging)?),
+        Engine::sled => migrate(source, &SledStore::new(sled::open(staging)?)),
+        Engine::lsm => migrate(source, &LsmStore::open(staging)?),
+    }
+}
+
+/// Returns `<dir>.<suffix>` next to `dir`.
+fn sibling(dir: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(dir.file_name().unwrap_or_default());
+    name.push(".");
+    name.push(suffix);
+    dir.with_file_name(name)
+}
+
+fn current_engine(dir: &Path) -> Result<Option<Engine>> {
+    let engine = dir.join("engine");
+    if !engine.exists() {
+        return Ok(None);
+    }
+    match std::fs::read_to_string(engine)?.parse() {
+        Ok(engine) => Ok(Some(engine)),
+        Err(e) => {
+            warn!("The content of engine file is invalid: {}", e);
+            Ok(None)
+        }
+    }
+}
+
+/// Refuses to touch directories that belong to another engine.
+fn check_engine(dir: &Path) -> Result<()> {
+    if current_engine(dir)?.is_some_and(|engine| engine != Engine::kvs) {
+        error!("Wrong engine!");
+        exit(1);
+    }
+    Ok(())
+}
@@ -1,7 +1,9 @@
 use clap::AppSettings;
-use kvs::{KvsClient, Result};
+use kvs::{Event, Health, KvsClient, Result, WAIT_BUCKETS};
+use std::io::{self, Write};
 use std::net::SocketAddr;
 use std::process::exit;
+use std::time::Duration;
 use structopt::StructOpt;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
@@ -42,6 +44,69 @@ enum Command {
         key: String,
         #[structopt(name = "VALUE", help = "The string vlaue of the key")]
         value: String,
+        #[structopt(
+            long,
+            help = "Expires the key after the given number of seconds",
+            value_name = "SECONDS"
+        )]
+        ttl: Option<u64>,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "incr",
+        about = "Atomically add to the integer value of a key and print the result",
+        raw(setting = "AppSettings::AllowNegativeNumbers")
+    )]
+    Incr {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(
+            name = "DELTA",
+            help = "The amount to add, may be negative",
+            default_value = "1"
+        )]
+        delta: i64,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "watch",
+        about = "Print every change of keys starting with a prefix as it happens"
+    )]
+    Watch {
+        #[structopt(
+            name = "PREFIX",
+            help = "A key prefix, all keys by default",
+            default_value = ""
+        )]
+        prefix: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_FORMAT"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "stats",
+        about = "Print the counters of the server's engine and pool"
+    )]
+    Stats {
         #[structopt(
             long,
             help = "Sets the server address",
@@ -84,14 +149,68 @@ fn run(opt: Opt) -> Result<()> {
                 println!("Key not found");
             }
         }
-        Command::Set { key, value, addr } => {
+        Command::Set {
+            key,
+            value,
+            ttl,
+            addr,
+        } => {
             let mut client = KvsClient::connect(addr)?;
-            client.set(key, value)?;
+            match ttl {
+                Some(ttl) => client.set_with_ttl(key, value, Duration::from_secs(ttl))?,
+                None => client.set(key, value)?,
+            }
         }
         Command::Remove { key, addr } => {
             let mut client = KvsClient::connect(addr)?;
             client.remove(key)?;
         }
+        Command::Incr { key, delta, addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            println!("{}", client.incr(key, delta)?);
+        }
+        Command::Stats { addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            let stats = client.stats()?;
+            if let Some(keys) = stats.keys {
+                println!("keys {}", keys);
+            }
+            println!("evictions {}", stats.evictions);
+            match stats.health {
+                Health::Ok => println!("health ok"),
+                Health::ReadOnly(cause) => println!("health read-only: {}", cause),
+            }
+            let pool = client.pool_stats()?;
+            println!("active-workers {}", pool.active_workers);
+            println!("idle-workers {}", pool.idle_workers);
+            println!("queue-depth {}", pool.queue_depth);
+            println!("jobs-completed {}", pool.jobs_completed);
+            println!("jobs-panicked {}", pool.jobs_panicked);
+            for (bound, count) in pool.queue_wait.buckets() {
+                match bound {
+                    Some(bound) => println!("queue-wait <{:?} {}", bound, count),
+                    None => println!(
+                        "queue-wait >={:?} {}",
+                        WAIT_BUCKETS[WAIT_BUCKETS.len() - 1],
+                        count
+                    ),
+                }
+            }
+        }
+        Command::Watch { prefix, addr } => {
+            let client = KvsClient::connect(addr)?;
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            for event in client.watch(prefix)? {
+                match event? {
+                    Event::Set { seq, key, value } => {
+                        writeln!(stdout, "{} set {} {}", seq, key, value)?
+                    }
+                    Event::Remove { seq, key } => writeln!(stdout, "{} rm {}", seq, key)?,
+                }
+                stdout.flush()?;
+            }
+        }
     }
     Ok(())
 }
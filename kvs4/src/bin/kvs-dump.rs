@@ -0,0 +1,108 @@
+use clap::arg_enum;
+use kvs::{dump, DumpFormat, KvStoreFollower, LsmStore, Result, SledStore};
+use log::{info, warn, LevelFilter};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use structopt::StructOpt;
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Engine {
+        kvs,
+        sled,
+        lsm
+    }
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Format {
+        json,
+        binary
+    }
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "kvs-dump")]
+struct Opt {
+    #[structopt(
+        long,
+        help = "Sets the data directory",
+        value_name = "DIR",
+        default_value = ".",
+        parse(from_os_str)
+    )]
+    dir: PathBuf,
+    #[structopt(
+        long,
+        help = "Sets the storage engine, detected from the data directory by default",
+        value_name = "ENGINE-NAME",
+        raw(possible_values = "&Engine::variants()")
+    )]
+    engine: Option<Engine>,
+    #[structopt(
+        long,
+        help = "Sets the dump format",
+        value_name = "FORMAT",
+        default_value = "json",
+        raw(possible_values = "&Format::variants()")
+    )]
+    format: Format,
+    #[structopt(
+        name = "OUTPUT",
+        help = "The file to write the dump to, stdout by default",
+        parse(from_os_str)
+    )]
+    output: Option<PathBuf>,
+}
+
+fn main() {
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    let engine = match opt.engine {
+        Some(engine) => engine,
+        None => current_engine(&opt.dir)?.unwrap_or(Engine::kvs),
+    };
+    let format = match opt.format {
+        Format::json => DumpFormat::JsonLines,
+        Format::binary => DumpFormat::Binary,
+    };
+    let output: Box<dyn Write> = match &opt.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+
+    let count = match engine {
+        // a follower reads the logs without touching the directory
+        Engine::kvs => dump(&KvStoreFollower::open(&opt.dir)?, output, format)?,
+        Engine::sled => dump(&SledStore::new(sled::open(&opt.dir)?), output, format)?,
+        Engine::lsm => dump(&LsmStore::open(&opt.dir)?, output, format)?,
+    };
+    info!("Dumped {} keys from {} engine", count, engine);
+    Ok(())
+}
+
+fn current_engine(dir: &Path) -> Result<Option<Engine>> {
+    let engine = dir.join("engine");
+    if !engine.exists() {
+        return Ok(None);
+    }
+    match std::fs::read_to_string(engine)?.parse() {
+        Ok(engine) => Ok(Some(engine)),
+        Err(e) => {
+            warn!("The content of engine file is invalid: {}", e);
+            Ok(None)
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use clap::arg_enum;
+use kvs::{restore, DumpFormat, DumpReader, KvStore, LsmStore, Result, SledStore};
+use log::{error, info, warn, LevelFilter};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use structopt::StructOpt;
+
+const DEFAULT_ENGINE: Engine = Engine::kvs;
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Engine {
+        kvs,
+        sled,
+        lsm
+    }
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Format {
+        json,
+        binary
+    }
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "kvs-restore")]
+struct Opt {
+    #[structopt(
+        long,
+        help = "Sets the data directory",
+        value_name = "DIR",
+        default_value = ".",
+        parse(from_os_str)
+    )]
+    dir: PathBuf,
+    #[structopt(
+        long,
+        help = "Sets the storage engine",
+        value_name = "ENGINE-NAME",
+        raw(possible_values = "&Engine::variants()")
+    )]
+    engine: Option<Engine>,
+    #[structopt(
+        long,
+        help = "Sets the dump format",
+        value_name = "FORMAT",
+        default_value = "json",
+        raw(possible_values = "&Format::variants()")
+    )]
+    format: Format,
+    #[structopt(
+        long,
+        help = "Writes a single fresh generation instead of replaying sets (kvs engine, empty directory only)"
+    )]
+    bulk: bool,
+    #[structopt(
+        name = "INPUT",
+        help = "The dump file to read, stdin by default",
+        parse(from_os_str)
+    )]
+    input: Option<PathBuf>,
+}
+
+fn main() {
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+    let mut opt = Opt::from_args();
+    let res = current_engine(&opt.dir).and_then(|curr_engine| {
+        if opt.engine.is_none() {
+            opt.engine = curr_engine;
+        }
+        if curr_engine.is_some() && opt.engine != curr_engine {
+            error!("Wrong engine!");
+            exit(1);
+        }
+        run(opt)
+    });
+
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
+    if opt.bulk && engine != Engine::kvs {
+        error!("Bulk load is only supported by the kvs engine");
+        exit(1);
+    }
+    let format = match opt.format {
+        Format::json => DumpFormat::JsonLines,
+        Format::binary => DumpFormat::Binary,
+    };
+    let input: Box<dyn Read> = match &opt.input {
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(io::stdin()),
+    };
+
+    let count = if opt.bulk {
+        let mut count = 0;
+        let entries = DumpReader::new(input, format)?.inspect(|_| count += 1);
+        KvStore::bulk_load(&opt.dir, entries)?;
+        // only now, so that a failed load leaves nothing behind
+        std::fs::write(opt.dir.join("engine"), format!("{}", engine))?;
+        count
+    } else {
+        std::fs::create_dir_all(&opt.dir)?;
+        std::fs::write(opt.dir.join("engine"), format!("{}", engine))?;
+        match engine {
+            Engine::kvs => restore(&KvStore::open(&opt.dir)?, input, format)?,
+            Engine::sled => restore(&SledStore::new(sled::open(&opt.dir)?), input, format)?,
+            Engine::lsm => restore(&LsmStore::open(&opt.dir)?, input, format)?,
+        }
+    };
+    info!("Restored {} keys into {} engine", count, engine);
+    Ok(())
+}
+
+fn current_engine(dir: &Path) -> Result<Option<Engine>> {
+    let engine = dir.join("engine");
+    if !engine.exists() {
+        return Ok(None);
+    }
+    match std::fs::read_to_string(engine)?.parse() {
+        Ok(engine) => Ok(Some(engine)),
+        Err(e) => {
+            warn!("The content of engine file is invalid: {}", e);
+            Ok(None)
+        }
+    }
+}
@@ -1,22 +1,46 @@
 use clap::arg_enum;
-use kvs::Result;
-use kvs::{KvStore, KvsEngine, KvsServer, SledStore};
+use kvs::{
+    swap_pending, Capacity, DirLock, ElasticThreadPool, EvictionPolicy, KvStore, KvStoreOptions,
+    KvsEngine, KvsServer, LsmStore, MemStore, NaiveThreadPool, RayonThreadPool,
+    SharedQueueThreadPool, SledStore, ThreadPool, WorkStealingThreadPool,
+};
+use kvs::{KvsError, Result};
 use log::{error, info, warn, LevelFilter};
 use std::env::current_dir;
 use std::net::SocketAddr;
 use std::process::exit;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 use structopt::StructOpt;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const ADDRESS_FORMAT: &str = "IP:PORT";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
+const DEFAULT_POOL: Pool = Pool::shared;
+/// How long requests being served on SIGINT/SIGTERM are given to finish.
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(5);
 
 arg_enum! {
     #[allow(non_camel_case_types)]
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     enum Engine {
         kvs,
-        sled
+        sled,
+        lsm,
+        memory
+    }
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Pool {
+        naive,
+        shared,
+        rayon,
+        elastic,
+        stealing
     }
 }
 
@@ -38,6 +62,57 @@ struct Opt {
         raw(possible_values = "&Engine::variants()")
     )]
     engine: Option<Engine>,
+    #[structopt(
+        long = "max-keys",
+        help = "Evicts keys beyond this many (not supported by the lsm engine)",
+        value_name = "KEYS"
+    )]
+    max_keys: Option<u64>,
+    #[structopt(
+        long = "max-bytes",
+        help = "Evicts keys beyond this many live bytes (not supported by the lsm engine)",
+        value_name = "BYTES"
+    )]
+    max_bytes: Option<u64>,
+    #[structopt(
+        long,
+        help = "Sets which keys are evicted first",
+        value_name = "POLICY",
+        default_value = "lru",
+        raw(possible_values = "&EvictionPolicy::variants()"),
+        parse(try_from_str)
+    )]
+    eviction: EvictionPolicy,
+    #[structopt(
+        long = "compact-index",
+        help = "Keeps key hashes instead of keys in memory (kvs engine only)"
+    )]
+    compact_index: bool,
+    #[structopt(
+        long,
+        help = "Sets the number of worker threads, or the most the elastic pool grows to [default: number of CPUs]",
+        value_name = "THREADS"
+    )]
+    threads: Option<u32>,
+    #[structopt(
+        long,
+        help = "Sets the thread pool serving connections",
+        value_name = "POOL-NAME",
+        raw(possible_values = "&Pool::variants()")
+    )]
+    pool: Option<Pool>,
+    #[structopt(
+        long = "queue-capacity",
+        help = "Rejects clients as busy while this many wait for a worker",
+        value_name = "CONNECTIONS"
+    )]
+    queue_capacity: Option<usize>,
+    #[structopt(
+        long = "max-watches",
+        help = "Tells clients the server is busy while this many watches are streamed",
+        value_name = "WATCHES"
+    )]
+    max_watches: Option<usize>,
 }
 
 fn main() {
@@ -47,9 +122,15 @@ fn main() {
         if opt.engine.is_none() {
             opt.engine = curr_engine;
         }
-        if curr_engine.is_some() && opt.engine != curr_engine {
-            error!("Wrong engine!");
-            exit(1);
+        if let (Some(curr), Some(wanted)) = (curr_engine, opt.engine) {
+            // the memory engine leaves the data directory alone
+            if curr != wanted && wanted != Engine::memory {
+                error!(
+                    "Wrong engine! The data is stored by {}, use `kvs-admin migrate --from {} --to {}` to convert it",
+                    curr, curr, wanted
+                );
+                exit(1);
+            }
         }
         run(opt)
     });
@@ -65,17 +146,130 @@ fn run(opt: Opt) -> Result<()> {
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
     info!("Listening on {}", opt.addr);
-    std::fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
+    let pool = PoolOptions {
+        kind: opt.pool.unwrap_or(DEFAULT_POOL),
+        threads: match opt.threads {
+            Some(0) => {
+                return Err(KvsError::StringErr(
+                    "--threads must be at least 1".to_owned(),
+                ))
+            }
+            Some(threads) => threads,
+            None => thread::available_parallelism().map_or(1, |n| n.get() as u32),
+        },
+        queue_capacity: opt.queue_capacity,
+        max_watches: opt.max_watches,
+    };
+    info!("Thread pool: {} with {} threads", pool.kind, pool.threads);
+    if let Some(capacity) = pool.queue_capacity {
+        info!("Queue capacity: {} connections", capacity);
+    }
+    if let Some(max) = pool.max_watches {
+        info!("Max watches: {}", max);
+    }
+    let capacity = Capacity {
+        max_keys: opt.max_keys,
+        max_bytes: opt.max_bytes,
+        policy: opt.eviction,
+    };
+    if !capacity.is_unlimited() {
+        if engine == Engine::lsm {
+            return Err(KvsError::StringErr(
+                "--max-keys and --max-bytes are not supported by the lsm engine".to_owned(),
+            ));
+        }
+        info!("Capacity: {:?}", capacity);
+    }
+    if opt.compact_index && engine != Engine::kvs {
+        return Err(KvsError::StringErr(
+            "--compact-index is only supported by the kvs engine".to_owned(),
+        ));
+    }
+    // held until the server stops, so that `kvs-admin migrate` keeps off the data
+    let _lock = match engine {
+        Engine::memory => None,
+        _ => {
+            let dir = current_dir()?;
+            if swap_pending(&dir) {
+                return Err(KvsError::StringErr(format!(
+                    "A migration of {} was interrupted, run `kvs-admin migrate` again to finish it",
+                    dir.display()
+                )));
+            }
+            let lock = DirLock::acquire(&dir)?;
+            std::fs::write(dir.join("engine"), format!("{}", engine))?;
+            Some(lock)
+        }
+    };
 
     match engine {
-        Engine::kvs => run_with_engine(KvStore::open(current_dir()?)?, opt.addr),
-        Engine::sled => run_with_engine(SledStore::new(sled::open(current_dir()?)?), opt.addr),
+        Engine::kvs => {
+            let options = KvStoreOptions {
+                capacity,
+                compact_index: opt.compact_index,
+            };
+            run_with_engine(KvStore::open_with(current_dir()?, options)?, pool, opt.addr)
+        }
+        Engine::sled => run_with_engine(
+            SledStore::with_capacity(sled::open(current_dir()?)?, capacity)?,
+            pool,
+            opt.addr,
+        ),
+        Engine::lsm => run_with_engine(LsmStore::open(current_dir()?)?, pool, opt.addr),
+        Engine::memory => run_with_engine(MemStore::with_capacity(capacity), pool, opt.addr),
     }
 }
 
-fn run_with_engine<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let server = KvsServer::new(engine);
-    server.run(addr)
+fn run_with_engine<E: KvsEngine>(engine: E, pool: PoolOptions, addr: SocketAddr) -> Result<()> {
+    match pool.kind {
+        Pool::naive => run_with_pool(engine, pool.build::<NaiveThreadPool>()?, &pool, addr),
+        Pool::shared => run_with_pool(engine, pool.build::<SharedQueueThreadPool>()?, &pool, addr),
+        Pool::rayon => run_with_pool(engine, pool.build::<RayonThreadPool>()?, &pool, addr),
+        Pool::elastic => run_with_pool(engine, pool.build::<ElasticThreadPool>()?, &pool, addr),
+        Pool::stealing => {
+            run_with_pool(engine, pool.build::<WorkStealingThreadPool>()?, &pool, addr)
+        }
+    }
+}
+
+fn run_with_pool<E: KvsEngine, P: ThreadPool>(
+    engine: E,
+    pool: P,
+    options: &PoolOptions,
+    addr: SocketAddr,
+) -> Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    ctrlc::set_handler(move || {
+        let _ = sender.send(());
+    })
+    .map_err(|e| KvsError::StringErr(format!("Cannot handle signals: {}", e)))?;
+
+    let mut server = KvsServer::new(engine, pool);
+    if let Some(max) = options.max_watches {
+        server = server.max_watches(max);
+    }
+    let handle = server.start(addr)?;
+    let _ = receiver.recv();
+    info!("Shutting down");
+    handle.shutdown(SHUTDOWN_DEADLINE)
+}
+
+/// How to build the thread pool serving connections, and how many watches are
+/// streamed beside it.
+struct PoolOptions {
+    kind: Pool,
+    threads: u32,
+    queue_capacity: Option<usize>,
+    max_watches: Option<usize>,
+}
+
+impl PoolOptions {
+    fn build<P: ThreadPool>(&self) -> Result<P> {
+        match self.queue_capacity {
+            Some(capacity) => P::with_queue_capacity(self.threads, capacity),
+            None => P::new(self.threads),
+        }
+    }
 }
 
 fn current_engine() -> Result<Option<Engine>> {
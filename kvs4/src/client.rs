@@ -1,11 +1,15 @@
-use crate::common::{GetResponse, Request, SetOrRemoveResponse};
-use crate::KvsError;
+use crate::common::{
+    GetResponse, IncrResponse, PoolStatsResponse, Request, SetOrRemoveResponse, StatsResponse,
+    Utf8Chunks, ValueChunk, WatchResponse,
+};
 use crate::Result;
+use crate::{EngineStats, Event, KvsError, PoolStats};
 use serde::Deserialize;
 use serde_json::de::IoRead;
 use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
 
 pub struct KvsClient {
     reader: Deserializer<IoRead<BufReader<TcpStream>>>,
@@ -28,17 +32,34 @@ impl KvsClient {
         let resp = GetResponse::deserialize(&mut self.reader)?;
         match resp {
             GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(msg) => Err(KvsError::KeyNotFound),
+            GetResponse::Err(msg) => Err(server_error(msg)),
         }
     }
 
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
+        self.send_set(Request::Set {
+            key,
+            value,
+            ttl: None,
+        })
+    }
+
+    /// Sets `key` to `value` until `ttl` has passed, see `KvsEngine::set_with_ttl`.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.send_set(Request::Set {
+            key,
+            value,
+            ttl: Some(ttl.as_millis().try_into().unwrap_or(u64::MAX)),
+        })
+    }
+
+    fn send_set(&mut self, req: Request) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, &req)?;
         self.writer.flush()?;
         let resp = GetResponse::deserialize(&mut self.reader)?;
         match resp {
             GetResponse::Ok(_) => Ok(()),
-            GetResponse::Err(e) => Err(KvsError::KeyNotFound),
+            GetResponse::Err(msg) => Err(server_error(msg)),
         }
     }
 
@@ -48,7 +69,173 @@ impl KvsClient {
         let resp = GetResponse::deserialize(&mut self.reader)?;
         match resp {
             GetResponse::Ok(_) => Ok(()),
-            GetResponse::Err(msg) => Err(KvsError::KeyNotFound),
+            GetResponse::Err(msg) => Err(server_error(msg)),
+        }
+    }
+
+    /// Adds `delta` to the integer stored at `key` and returns the result.
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        serde_json::to_writer(&mut self.writer, &Request::Incr { key, delta })?;
+        self.writer.flush()?;
+        match IncrResponse::deserialize(&mut self.reader)? {
+            IncrResponse::Ok(value) => Ok(value),
+            IncrResponse::Err(msg) => Err(server_error(msg)),
+        }
+    }
+
+    /// Returns the counters of the server's engine.
+    pub fn stats(&mut self) -> Result<EngineStats> {
+        serde_json::to_writer(&mut self.writer, &Request::Stats)?;
+        self.writer.flush()?;
+        match StatsResponse::deserialize(&mut self.reader)? {
+            StatsResponse::Ok(stats) => Ok(stats),
+            StatsResponse::Err(msg) => Err(server_error(msg)),
         }
     }
+
+    /// Returns the counters of the server's thread pool.
+    pub fn pool_stats(&mut self) -> Result<PoolStats> {
+        serde_json::to_writer(&mut self.writer, &Request::PoolStats)?;
+        self.writer.flush()?;
+        match PoolStatsResponse::deserialize(&mut self.reader)? {
+            PoolStatsResponse::Ok(stats) => Ok(stats),
+            PoolStatsResponse::Err(msg) => Err(server_error(msg)),
+        }
+    }
+
+    /// Sets `key` to the next `len` bytes of `reader`, which must be UTF-8.
+    /// The value is sent in chunks, so it never is in memory as a whole.
+    ///
+    /// If `reader` fails or ends early the server still waits for the rest of the
+    /// value, so the client must not be used any more.
+    pub fn set_from_reader<R: Read>(&mut self, key: String, len: u64, reader: R) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, &Request::SetStream { key, len })?;
+        let mut sent = 0;
+        for chunk in Utf8Chunks::new(reader.take(len)) {
+            let chunk = chunk.map_err(|e| KvsError::InvalidValue(e.to_string()))?;
+            sent += chunk.len() as u64;
+            serde_json::to_writer(&mut self.writer, &Request::Chunk(chunk))?;
+        }
+        self.writer.flush()?;
+        if sent < len {
+            return Err(KvsError::InvalidValue(format!(
+                "expected {} bytes, got {}",
+                len, sent
+            )));
+        }
+        match SetOrRemoveResponse::deserialize(&mut self.reader)? {
+            SetOrRemoveResponse::Ok(()) => Ok(()),
+            SetOrRemoveResponse::Err(msg) => Err(KvsError::StringErr(msg)),
+        }
+    }
+
+    /// Returns a reader of the value of `key`, which is streamed from the server in
+    /// chunks. Dropping the reader skips the rest of the value.
+    pub fn get_reader(&mut self, key: String) -> Result<Option<ValueStream<'_>>> {
+        serde_json::to_writer(&mut self.writer, &Request::GetStream { key })?;
+        self.writer.flush()?;
+        let chunk = match ValueChunk::deserialize(&mut self.reader)? {
+            ValueChunk::Chunk(chunk) => chunk,
+            ValueChunk::End => String::new(),
+            ValueChunk::NotFound => return Ok(None),
+            ValueChunk::Err(msg) => return Err(KvsError::StringErr(msg)),
+        };
+        Ok(Some(ValueStream {
+            done: chunk.is_empty(),
+            client: self,
+            chunk: chunk.into_bytes(),
+            pos: 0,
+        }))
+    }
+
+    /// Subscribes to all later mutations of keys starting with `prefix`.
+    /// The connection is dedicated to the subscription from then on.
+    pub fn watch(mut self, prefix: String) -> Result<Watch> {
+        serde_json::to_writer(&mut self.writer, &Request::Watch { prefix })?;
+        self.writer.flush()?;
+        match WatchResponse::deserialize(&mut self.reader)? {
+            WatchResponse::Subscribed => Ok(Watch { client: self }),
+            WatchResponse::Event(_) => Err(KvsError::UnexpectedCommandErr),
+            WatchResponse::Err(msg) => Err(KvsError::StringErr(msg)),
+        }
+    }
+}
+
+/// The stream of events of a `KvsClient::watch` subscription.
+/// It ends when the server closes the connection.
+pub struct Watch {
+    client: KvsClient,
+}
+
+impl Iterator for Watch {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Result<Event>> {
+        match WatchResponse::deserialize(&mut self.client.reader) {
+            Ok(WatchResponse::Event(event)) => Some(Ok(event)),
+            Ok(WatchResponse::Subscribed) => Some(Err(KvsError::UnexpectedCommandErr)),
+            Ok(WatchResponse::Err(msg)) => Some(Err(KvsError::StringErr(msg))),
+            Err(e) if e.is_eof() => None,
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+/// Turns the message of an error on the server back into an error, e.g. to tell a
+/// missing key from a busy server.
+fn server_error(msg: String) -> KvsError {
+    if msg == KvsError::KeyNotFound.to_string() {
+        return KvsError::KeyNotFound;
+    }
+    if msg == KvsError::IntegerOverflow.to_string() {
+        return KvsError::IntegerOverflow;
+    }
+    let key = msg
+        .strip_prefix("Value of ")
+        .and_then(|rest| rest.strip_suffix(" is not an integer"));
+    if let Some(key) = key {
+        return KvsError::NotAnInteger(key.to_owned());
+    }
+    match msg.strip_suffix(" is not supported by this engine") {
+        Some(feature) => KvsError::Unsupported(feature.to_owned()),
+        None => KvsError::StringErr(msg),
+    }
+}
+
+/// A value streamed from the server by `KvsClient::get_reader`.
+pub struct ValueStream<'a> {
+    client: &'a mut KvsClient,
+    chunk: Vec<u8>,
+    pos: usize,
+    // set once `ValueChunk::End` or an error is received
+    done: bool,
+}
+
+impl Read for ValueStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() && !self.done {
+            self.done = true;
+            match ValueChunk::deserialize(&mut self.client.reader)? {
+                ValueChunk::Chunk(chunk) => {
+                    self.chunk = chunk.into_bytes();
+                    self.pos = 0;
+                    self.done = false;
+                }
+                ValueChunk::End => {}
+                ValueChunk::NotFound => return Err(io::ErrorKind::InvalidData.into()),
+                ValueChunk::Err(msg) => return Err(io::Error::other(msg)),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Drop for ValueStream<'_> {
+    // the rest of the value must not be taken for the response to the next request
+    fn drop(&mut self) {
+        let _ = io::copy(self, &mut io::sink());
+    }
 }
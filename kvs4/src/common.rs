@@ -1,10 +1,46 @@
+use crate::{EngineStats, Event, PoolStats};
 use serde::{Deserialize, Serialize};
+use std::io::{self, Read};
+
+/// Size of the chunks streamed values are sent in.
+const CHUNK_SIZE: usize = 64 * 1024;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+        /// Milliseconds until the key expires, see `KvsEngine::set_with_ttl`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl: Option<u64>,
+    },
+    Remove {
+        key: String,
+    },
+    Watch {
+        prefix: String,
+    },
+    Incr {
+        key: String,
+        delta: i64,
+    },
+    Stats,
+    /// Asks for the counters of the thread pool serving the connection
+    PoolStats,
+    /// Sets `key` to a value of `len` bytes, sent in the `Chunk`s that follow
+    SetStream {
+        key: String,
+        len: u64,
+    },
+    /// A piece of the value of a `SetStream`
+    Chunk(String),
+    /// Asks for the value of `key` as a stream of `ValueChunk`s
+    GetStream {
+        key: String,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -18,3 +54,102 @@ pub enum SetOrRemoveResponse {
     Ok(()),
     Err(String),
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum IncrResponse {
+    Ok(i64),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StatsResponse {
+    Ok(EngineStats),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PoolStatsResponse {
+    Ok(PoolStats),
+    Err(String),
+}
+
+/// Responses streamed on a connection after a `Request::Watch`.
+/// `Subscribed` acknowledges the request and is followed by one `Event` per mutation.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum WatchResponse {
+    Subscribed,
+    Event(Event),
+    Err(String),
+}
+
+/// Responses to a `Request::GetStream`: the value in `Chunk`s followed by `End`, or
+/// `NotFound`. `Err` ends the stream at any point.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ValueChunk {
+    Chunk(String),
+    End,
+    NotFound,
+    Err(String),
+}
+
+/// Sent instead of any response to a client the server has no room for. Every
+/// response has an `Err(String)` variant, so it reads as an error whatever the
+/// client asked.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum BusyResponse {
+    Err(String),
+}
+
+/// Splits what a reader yields into strings of up to `CHUNK_SIZE` bytes.
+/// A character is never split between two chunks. Invalid UTF-8 is an error.
+pub(crate) struct Utf8Chunks<R: Read> {
+    reader: R,
+    buf: Vec<u8>,
+    // bytes of a character that continues in the next chunk
+    carry: usize,
+}
+
+impl<R: Read> Utf8Chunks<R> {
+    pub fn new(reader: R) -> Self {
+        Utf8Chunks {
+            reader,
+            buf: vec![0; CHUNK_SIZE],
+            carry: 0,
+        }
+    }
+}
+
+impl<R: Read> Iterator for Utf8Chunks<R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        loop {
+            let n = match self.reader.read(&mut self.buf[self.carry..]) {
+                Ok(0) if self.carry == 0 => return None,
+                Ok(0) => return Some(Err(invalid_utf8("ends in the middle of a character"))),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Some(Err(e)),
+            };
+            let end = self.carry + n;
+            let valid = match std::str::from_utf8(&self.buf[..end]) {
+                Ok(_) => end,
+                Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                Err(e) => return Some(Err(invalid_utf8(&e.to_string()))),
+            };
+            let chunk = String::from_utf8(self.buf[..valid].to_vec()).unwrap();
+            self.buf.copy_within(valid..end, 0);
+            self.carry = end - valid;
+            if !chunk.is_empty() {
+                return Some(Ok(chunk));
+            }
+        }
+    }
+}
+
+fn invalid_utf8(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid UTF-8: {}", msg),
+    )
+}
@@ -0,0 +1,163 @@
+use crate::engines::KvsEngine;
+use crate::{KvStoreFollower, KvsError, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::de::IoRead;
+use serde_json::{Deserializer, StreamDeserializer};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+
+/// Magic bytes at the start of a binary dump.
+const BINARY_MAGIC: &[u8; 8] = b"KVSDUMP1";
+
+/// The portable formats a dump can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// One `{"key": .., "value": ..}` object per line.
+    JsonLines,
+    /// A magic header followed by length-prefixed key/value pairs.
+    /// Lengths are little-endian `u64`s.
+    Binary,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DumpRecord {
+    key: String,
+    value: String,
+}
+
+/// Something whose live key/value pairs can be dumped.
+///
+/// Every engine is one. A `KvStoreFollower` dumps a `KvStore` directory without
+/// modifying it, which opening a `KvStore` would.
+pub trait DumpSource {
+    /// Calls `f` with every live key and value.
+    fn for_each_pair<F>(&self, f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>;
+}
+
+impl<E: KvsEngine> DumpSource for E {
+    fn for_each_pair<F>(&self, f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        self.scan(f)
+    }
+}
+
+impl DumpSource for KvStoreFollower {
+    fn for_each_pair<F>(&self, f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        self.scan(f)
+    }
+}
+
+/// Streams every live key/value pair of `source` into `writer`.
+/// Returns the number of pairs written.
+pub fn dump<S: DumpSource, W: Write>(source: &S, writer: W, format: DumpFormat) -> Result<u64> {
+    let mut writer = BufWriter::new(writer);
+    let mut count = 0;
+    if format == DumpFormat::Binary {
+        writer.write_all(BINARY_MAGIC)?;
+    }
+    source.for_each_pair(|key, value| {
+        match format {
+            DumpFormat::JsonLines => {
+                serde_json::to_writer(&mut writer, &DumpRecord { key, value })?;
+                writer.write_all(b"\n")?;
+            }
+            DumpFormat::Binary => {
+                write_chunk(&mut writer, key.as_bytes())?;
+                write_chunk(&mut writer, value.as_bytes())?;
+            }
+        }
+        count += 1;
+        Ok(())
+    })?;
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Loads a dump into `engine` by replaying `set`s.
+/// Returns the number of pairs restored.
+pub fn restore<E: KvsEngine, R: Read>(engine: &E, reader: R, format: DumpFormat) -> Result<u64> {
+    let mut count = 0;
+    for entry in DumpReader::new(reader, format)? {
+        let (key, value) = entry?;
+        engine.set(key, value)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// An iterator over the key/value pairs of a dump.
+pub struct DumpReader<R: Read> {
+    inner: DumpReaderInner<R>,
+}
+
+enum DumpReaderInner<R: Read> {
+    JsonLines(StreamDeserializer<'static, IoRead<BufReader<R>>, DumpRecord>),
+    Binary(BufReader<R>),
+}
+
+impl<R: Read> DumpReader<R> {
+    /// Creates a reader, checking the header if the format has one.
+    pub fn new(reader: R, format: DumpFormat) -> Result<Self> {
+        let mut reader = BufReader::new(reader);
+        let inner = match format {
+            DumpFormat::JsonLines => DumpReaderInner::JsonLines(
+                Deserializer::from_reader(reader).into_iter::<DumpRecord>(),
+            ),
+            DumpFormat::Binary => {
+                let mut magic = [0; 8];
+                reader.read_exact(&mut magic)?;
+                if &magic != BINARY_MAGIC {
+                    return Err(KvsError::InvalidDump("bad magic header".to_owned()));
+                }
+                DumpReaderInner::Binary(reader)
+            }
+        };
+        Ok(DumpReader { inner })
+    }
+}
+
+impl<R: Read> Iterator for DumpReader<R> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.inner {
+            DumpReaderInner::JsonLines(stream) => stream
+                .next()
+                .map(|record| Ok(record.map(|r| (r.key, r.value))?)),
+            DumpReaderInner::Binary(reader) => match reader.fill_buf() {
+                Ok([]) => None,
+                Ok(_) => Some(read_binary_record(reader)),
+                Err(e) => Some(Err(e.into())),
+            },
+        }
+    }
+}
+
+fn write_chunk<W: Write>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+fn read_chunk<R: Read>(reader: &mut R) -> Result<String> {
+    let mut len = [0; 8];
+    reader.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len);
+    let mut bytes = Vec::new();
+    let read = reader.take(len).read_to_end(&mut bytes)?;
+    if read as u64 != len {
+        return Err(KvsError::InvalidDump("truncated record".to_owned()));
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+fn read_binary_record<R: Read>(reader: &mut R) -> Result<(String, String)> {
+    let key = read_chunk(reader)?;
+    let value = read_chunk(reader)?;
+    Ok((key, value))
+}
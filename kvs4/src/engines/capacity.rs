@@ -0,0 +1,247 @@
+use crate::KvsError;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Which keys a store gives up first when it is over capacity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// The least recently read or written key
+    #[default]
+    Lru,
+    /// The least frequently read or written key, the least recently used among equals
+    Lfu,
+    /// Any key
+    Random,
+    /// The key written the longest time ago. Reads don't count.
+    Fifo,
+    /// The key that expires first, see `KvsEngine::set_with_ttl`. Keys without a TTL
+    /// go after all others, the one written the longest time ago first.
+    TtlFirst,
+}
+
+impl EvictionPolicy {
+    /// Names accepted by `from_str`, in the same order as the variants.
+    pub fn variants() -> [&'static str; 5] {
+        ["lru", "lfu", "random", "fifo", "ttl-first"]
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self, KvsError> {
+        match s {
+            "lru" => Ok(EvictionPolicy::Lru),
+            "lfu" => Ok(EvictionPolicy::Lfu),
+            "random" => Ok(EvictionPolicy::Random),
+            "fifo" => Ok(EvictionPolicy::Fifo),
+            "ttl-first" => Ok(EvictionPolicy::TtlFirst),
+            _ => Err(KvsError::StringErr(format!(
+                "Unknown eviction policy {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            EvictionPolicy::Lru => "lru",
+            EvictionPolicy::Lfu => "lfu",
+            EvictionPolicy::Random => "random",
+            EvictionPolicy::Fifo => "fifo",
+            EvictionPolicy::TtlFirst => "ttl-first",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Limits on the live data of a store. `None` means unlimited.
+///
+/// Once a write takes the store over a limit, keys are evicted according to
+/// `policy` until it is back within all limits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capacity {
+    /// Maximum number of live keys
+    pub max_keys: Option<u64>,
+    /// Maximum number of live bytes, as measured by the engine
+    pub max_bytes: Option<u64>,
+    pub policy: EvictionPolicy,
+}
+
+impl Capacity {
+    /// Returns `true` if no limit is set.
+    pub fn is_unlimited(&self) -> bool {
+        self.max_keys.is_none() && self.max_bytes.is_none()
+    }
+}
+
+struct Entry {
+    // position in `Evictor::order`
+    rank: (u64, u64),
+    size: u64,
+    hits: u64,
+    expires: Option<u64>,
+}
+
+/// Tracks the live keys of a store and picks the keys to evict.
+///
+/// Access history lives in memory only. After a restart the keys start out in the
+/// order the engine reports them.
+pub(crate) struct Evictor {
+    capacity: Capacity,
+    // bumped on every access, so larger ticks are more recent
+    clock: u64,
+    bytes: u64,
+    evictions: u64,
+    // state of the xorshift generator of the random policy
+    seed: u64,
+    entries: HashMap<String, Entry>,
+    // live keys, the next victim first
+    order: BTreeMap<(u64, u64), String>,
+}
+
+impl Evictor {
+    pub fn new(capacity: Capacity) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64);
+        Evictor {
+            capacity,
+            clock: 0,
+            bytes: 0,
+            evictions: 0,
+            seed: seed | 1,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// Records a read of `key`. Unknown keys are ignored.
+    pub fn touch(&mut self, key: &str) {
+        if self.entries.contains_key(key) {
+            self.access(key, None);
+        }
+    }
+
+    /// Records a write of `key`, which now takes `size` bytes and expires at
+    /// `expires`, in milliseconds since the Unix epoch.
+    pub fn insert(&mut self, key: &str, size: u64, expires: Option<u64>) {
+        self.access(key, Some((size, expires)));
+    }
+
+    /// Records that the keys a store already held are all inserted, so that the last
+    /// of them is not spared by `next_victim` like a key just written.
+    pub fn loaded(&mut self) {
+        self.clock += 1;
+    }
+
+    /// Records the removal of `key`.
+    pub fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.rank);
+            self.bytes -= entry.size;
+        }
+    }
+
+    /// Forgets the keys that must be evicted to get back within the capacity, and
+    /// returns them in eviction order.
+    ///
+    /// For stores whose removes cannot fail. Others go through `next_victim` and
+    /// `evicted`, so that a key is only forgotten once it is gone.
+    pub fn victims(&mut self) -> Vec<String> {
+        let mut victims = Vec::new();
+        while let Some(key) = self.next_victim() {
+            self.evicted(&key);
+            victims.push(key);
+        }
+        victims
+    }
+
+    /// Returns the key to evict next if the store is over capacity.
+    ///
+    /// The key accessed last goes only when it is the last one left, so a new key is
+    /// not evicted right away just for having few hits. That way a single entry larger
+    /// than `max_bytes` is still evicted.
+    pub fn next_victim(&self) -> Option<String> {
+        if !self.over_capacity() {
+            return None;
+        }
+        let rank = self
+            .order
+            .keys()
+            .find(|rank| rank.1 != self.clock)
+            .or_else(|| self.order.keys().next())?;
+        Some(self.order[rank].clone())
+    }
+
+    /// Records that the store evicted `key`.
+    pub fn evicted(&mut self, key: &str) {
+        self.remove(key);
+        self.evictions += 1;
+    }
+
+    /// Number of keys evicted so far.
+    pub fn evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    fn over_capacity(&self) -> bool {
+        self.capacity
+            .max_keys
+            .is_some_and(|max| self.entries.len() as u64 > max)
+            || self.capacity.max_bytes.is_some_and(|max| self.bytes > max)
+    }
+
+    /// Moves `key` to its new place in the eviction order. `write` holds the size
+    /// and expiry of a write.
+    fn access(&mut self, key: &str, write: Option<(u64, Option<u64>)>) {
+        self.clock += 1;
+        let tick = self.clock;
+        let old = self.entries.remove(key);
+        if let Some(old) = &old {
+            self.order.remove(&old.rank);
+            self.bytes -= old.size;
+        }
+
+        let hits = old.as_ref().map_or(0, |old| old.hits) + 1;
+        let (size, expires) = write
+            .or_else(|| old.as_ref().map(|old| (old.size, old.expires)))
+            .unwrap();
+        // the first half of the rank orders victims, the tick breaks ties
+        let rank = match (self.capacity.policy, &old) {
+            (EvictionPolicy::Lru, _) => (tick, tick),
+            (EvictionPolicy::Lfu, _) => (hits, tick),
+            (EvictionPolicy::Random, Some(old)) => (old.rank.0, tick),
+            (EvictionPolicy::Random, None) => (self.next_random(), tick),
+            // only writes move a key
+            (EvictionPolicy::Fifo | EvictionPolicy::TtlFirst, Some(old)) if write.is_none() => {
+                old.rank
+            }
+            (EvictionPolicy::Fifo, _) => (tick, tick),
+            (EvictionPolicy::TtlFirst, _) => (expires.unwrap_or(u64::MAX), tick),
+        };
+        self.bytes += size;
+        self.order.insert(rank, key.to_owned());
+        self.entries.insert(
+            key.to_owned(),
+            Entry {
+                rank,
+                size,
+                hits,
+                expires,
+            },
+        );
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        self.seed
+    }
+}
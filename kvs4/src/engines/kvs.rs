@@ -1,33 +1,78 @@
-use super::KvsEngine;
+use super::{
+    expiry, is_expired, parse_integer, Capacity, EngineStats, EvictionPolicy, Evictor, Health,
+    KvsEngine, Publisher, Subscription,
+};
 use crate::{KvsError, Result};
 
 use std::cell::RefCell;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use crossbeam_skiplist::SkipMap;
-
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
 use std::fs::{File, OpenOptions};
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Take, Write};
 use std::option::Option;
 use std::path::{Path, PathBuf};
 
+use log::{error, info};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 
+mod follower;
+mod format;
+mod index;
+mod snapshot;
+mod stream;
+mod verify;
+
+use self::format::{write_format, FORMAT_VERSION};
+use self::index::Index;
+use self::snapshot::Snapshot;
+use self::stream::{remove_spools, Spool, ValueReader};
+
+pub use self::follower::KvStoreFollower;
+pub use self::format::StoreFormat;
+pub use self::verify::{GenerationReport, RepairReport, VerifyReport};
+
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 
+/// How long a store stays read-only after a failed write before it tries again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
 /// Struct representing a command
 #[derive(Serialize, Deserialize, Debug)]
 enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        // milliseconds since the Unix epoch, left out for keys that never expire
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+}
+
+impl Command {
+    fn key(&self) -> &str {
+        match self {
+            Command::Set { key, .. } | Command::Remove { key } => key,
+        }
+    }
+
+    fn into_key(self) -> String {
+        match self {
+            Command::Set { key, .. } | Command::Remove { key } => key,
+        }
+    }
 }
 
 /// Represents the position and length of a json-serialized command in the log
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Serialize, Deserialize)]
 struct CommandPos {
     gen: u64,
     pos: u64,
@@ -156,6 +201,13 @@ impl KvStoreReader {
             Ok(serde_json::from_reader(cmd_reader)?)
         })
     }
+
+    /// Reads when the `Set` command at `cmd_pos` expires, without reading its value.
+    fn read_expiry(&self, cmd_pos: CommandPos) -> Result<Option<u64>> {
+        self.read_and(cmd_pos, |cmd_reader| {
+            Ok(ValueReader::new(BufReader::new(cmd_reader))?.expires())
+        })
+    }
 }
 
 impl Clone for KvStoreReader {
@@ -178,72 +230,263 @@ struct KvStoreWriter {
     // could be deleted during a compaction
     uncompacted: u64,
     path: Arc<PathBuf>,
-    index: Arc<SkipMap<String, CommandPos>>,
+    index: Arc<Index>,
+    publisher: Publisher,
+    evictor: Option<Arc<Mutex<Evictor>>>,
+    // set while writes fail
+    degraded: Option<Degraded>,
+}
+
+/// Why a `KvStoreWriter` refuses writes, and when it last tried one.
+struct Degraded {
+    cause: String,
+    last_attempt: Instant,
+    // where the log must be cut back to before the next write, if that failed too
+    rollback: Option<u64>,
 }
 
 impl KvStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::Set { key, value };
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self.index.get(&key) {
-                self.uncompacted += old_cmd.value().len;
+    fn set(&mut self, key: String, value: String, expires: Option<u64>) -> Result<()> {
+        let cmd = Command::Set {
+            key,
+            expires,
+            value,
+        };
+        let range = self.append(|writer| Ok(serde_json::to_writer(writer, &cmd)?))?;
+        if let Command::Set { key, value, .. } = cmd {
+            self.publisher.publish_set(&key, &value);
+            self.index_set(key, range, expires)?;
+        }
+        Ok(())
+    }
+
+    /// Like `set`, but copies the record of a value received into `spool`, so that
+    /// it is never held in memory.
+    fn set_from_spool(&mut self, key: String, spool: &Spool) -> Result<()> {
+        let range = self.append(|writer| spool.copy_to(writer))?;
+        // nobody sees the value of an event without subscribers
+        let value = if self.publisher.is_watched(&key) {
+            let cmd_pos = (self.current_gen, range.clone()).into();
+            match self.reader.read_command(cmd_pos)? {
+                Command::Set { value, .. } => value,
+                Command::Remove { .. } => return Err(KvsError::UnexpectedCommandErr),
             }
-            self.index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into());
+        } else {
+            String::new()
+        };
+        self.publisher.publish_set(&key, &value);
+        self.index_set(key, range, None)
+    }
+
+    /// Points `key` to the `Set` record just appended at `range`, which expires at
+    /// `expires`.
+    fn index_set(
+        &mut self,
+        key: String,
+        range: std::ops::Range<u64>,
+        expires: Option<u64>,
+    ) -> Result<()> {
+        let len = range.end - range.start;
+        let cmd_pos = (self.current_gen, range).into();
+        if let Some(evictor) = &self.evictor {
+            evictor.lock().unwrap().insert(&key, len, expires);
+        }
+        if let Some(old_cmd) = self.index.insert(key, cmd_pos, &self.reader)? {
+            self.uncompacted += old_cmd.len;
         }
+        self.evict();
         if self.uncompacted > COMPACTION_THRESHOLD {
             self.compact()?;
         }
         Ok(())
     }
 
-    // next
+    /// Removes `key`. An expired key is removed all the same, but reported as
+    /// missing, since it was already.
     fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
+        if let Some(cmd_pos) = self.index.get(&key, &self.reader)? {
+            let expired = is_expired(self.reader.read_expiry(cmd_pos)?);
             let cmd = Command::Remove { key };
-            let pos = self.writer.pos;
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
+            let range = self.append(|writer| Ok(serde_json::to_writer(writer, &cmd)?))?;
             if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("Key not found");
-                self.uncompacted += old_cmd.value().len;
+                let old_cmd = self
+                    .index
+                    .remove(&key, &self.reader)?
+                    .expect("Key not found");
+                self.uncompacted += old_cmd.len;
                 // the "remove" command itself can be deleted in the next compaction
                 // so we add its length to `uncompacted`.
-                self.uncompacted += self.writer.pos - pos;
+                self.uncompacted += range.end - range.start;
+                if let Some(evictor) = &self.evictor {
+                    evictor.lock().unwrap().remove(&key);
+                }
+                self.publisher.publish_remove(&key);
             }
             if self.uncompacted > COMPACTION_THRESHOLD {
                 self.compact();
             }
 
-            Ok(())
+            if expired {
+                Err(KvsError::KeyNotFound)
+            } else {
+                Ok(())
+            }
         } else {
             Err(KvsError::KeyNotFound)
         }
     }
 
+    /// Appends the record `write` writes to the current log and returns where it
+    /// ended up.
+    ///
+    /// A failed write may leave part of the record in the file or in the buffer of
+    /// the writer, so it is rolled back, and the store turns read-only. Every
+    /// `RETRY_INTERVAL` the next write is let through to find out if it succeeds again,
+    /// e.g. because disk space was freed.
+    fn append<F>(&mut self, write: F) -> Result<std::ops::Range<u64>>
+    where
+        F: FnOnce(&mut BufWriterWithPos<File>) -> Result<()>,
+    {
+        if let Some(degraded) = &mut self.degraded {
+            if degraded.last_attempt.elapsed() < RETRY_INTERVAL {
+                return Err(KvsError::ReadOnly(degraded.cause.clone()));
+            }
+            if let Some(pos) = degraded.rollback {
+                degraded.last_attempt = Instant::now();
+                let cause = degraded.cause.clone();
+                self.rollback(pos).map_err(|_| KvsError::ReadOnly(cause))?;
+            }
+        }
+
+        let pos = self.writer.pos;
+        let res = write(&mut self.writer).and_then(|()| Ok(self.writer.flush()?));
+        match res {
+            Ok(()) => {
+                if self.degraded.take().is_some() {
+                    info!("Writes to {} succeed again", self.path.display());
+                }
+                Ok(pos..self.writer.pos)
+            }
+            Err(e) => {
+                let cause = e.to_string();
+                error!(
+                    "Write to {} failed, serving reads only: {}",
+                    self.path.display(),
+                    cause
+                );
+                // a rollback that fails is tried again before the next write
+                let rollback = self.rollback(pos).err().map(|_| pos);
+                self.degraded = Some(Degraded {
+                    cause: cause.clone(),
+                    last_attempt: Instant::now(),
+                    rollback,
+                });
+                Err(KvsError::ReadOnly(cause))
+            }
+        }
+    }
+
+    /// Replaces the writer, whose buffer may still hold the rest of a failed record,
+    /// and cuts the current log back to `pos`.
+    fn rollback(&mut self, pos: u64) -> Result<()> {
+        let res = self.try_rollback(pos);
+        if let Err(e) = &res {
+            error!(
+                "Cannot roll back {} to offset {}: {}",
+                log_path(&self.path, self.current_gen).display(),
+                pos,
+                e
+            );
+        }
+        res
+    }
+
+    fn try_rollback(&mut self, pos: u64) -> Result<()> {
+        // The buffer goes before anything else can fail, since `BufWriter` would try
+        // to write it out on drop or along with the next record.
+        let file = self.writer.writer.get_ref().try_clone()?;
+        let failed = std::mem::replace(
+            &mut self.writer,
+            BufWriterWithPos {
+                writer: BufWriter::new(file),
+                pos,
+            },
+        );
+        let _ = failed.writer.into_parts();
+        // the file is opened for appending, so the next record goes right after `pos`
+        self.writer.writer.get_ref().set_len(pos)?;
+        Ok(())
+    }
+
+    fn incr_by(&mut self, key: String, delta: i64) -> Result<i64> {
+        let current = match self.index.read(&key, &self.reader)? {
+            Some(Command::Set { value, expires, .. }) if !is_expired(expires) => {
+                parse_integer(&key, &value)?
+            }
+            Some(Command::Set { .. }) | None => 0,
+            Some(Command::Remove { .. }) => return Err(KvsError::UnexpectedCommandErr),
+        };
+        let new = current
+            .checked_add(delta)
+            .ok_or(KvsError::IntegerOverflow)?;
+        self.set(key, new.to_string(), None)?;
+        Ok(new)
+    }
+
+    /// Removes the keys the evictor picks to get back within the capacity.
+    /// The removes are logged like any other, so evictions survive a restart.
+    ///
+    /// The write that took the store over capacity has succeeded by then, so a
+    /// failed remove is only logged. The evictor keeps the key and picks it again
+    /// after the next write.
+    fn evict(&mut self) {
+        let evictor = match &self.evictor {
+            Some(evictor) => Arc::clone(evictor),
+            None => return,
+        };
+        loop {
+            let victim = match evictor.lock().unwrap().next_victim() {
+                Some(victim) => victim,
+                None => return,
+            };
+            match self.remove(victim.clone()) {
+                Ok(()) => evictor.lock().unwrap().evicted(&victim),
+                Err(KvsError::KeyNotFound) => evictor.lock().unwrap().remove(&victim),
+                Err(e) => {
+                    error!("Cannot evict {}: {}", victim, e);
+                    return;
+                }
+            }
+        }
+    }
+
     /// Clears stale entries in the log
     fn compact(&mut self) -> Result<()> {
         // increase current gen by 2. current_gen + 1 is for the compaction file
         let compaction_gen = self.current_gen + 1;
         self.current_gen += 2;
         self.writer = new_log_file(&self.path, self.current_gen)?;
-        let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
+        let compaction_path = compaction_path(&self.path, compaction_gen);
+        let mut compaction_writer = BufWriterWithPos::new(File::create(&compaction_path)?)?;
 
+        let mut new_positions = Vec::with_capacity(self.index.len());
         let mut new_pos = 0; // pos in the new log file
-        for entry in self.index.iter() {
-            let len = self.reader.read_and(*entry.value(), |mut entry_reader| {
+        for cmd_pos in self.index.positions() {
+            let len = self.reader.read_and(cmd_pos, |mut entry_reader| {
                 Ok(std::io::copy(&mut entry_reader, &mut compaction_writer)?)
             })?;
-            self.index.insert(
-                entry.key().clone(),
-                (compaction_gen, new_pos..new_pos + len).into(),
-            );
+            new_positions.push((compaction_gen, new_pos..new_pos + len).into());
             new_pos += len;
         }
         compaction_writer.flush()?;
+        compaction_writer.writer.get_ref().sync_all()?;
+
+        // The compaction file only becomes a generation once it is complete. A crash in
+        // the middle of a compaction leaves an orphan `.compact` file instead of a partial
+        // log that `load` would replay.
+        std::fs::rename(&compaction_path, log_path(&self.path, compaction_gen))?;
+        // Only the writer modifies the index, so it is iterated in the same order again.
+        self.index.set_positions(new_positions)?;
 
         self.reader
             .safe_point
@@ -267,8 +510,52 @@ impl KvStoreWriter {
         }
         self.uncompacted = 0;
 
+        // the index is as small as it gets, a good time to save it
+        if let Err(e) = self.snapshot() {
+            error!(
+                "Cannot save index snapshot of {}: {}",
+                self.path.display(),
+                e
+            );
+        }
         Ok(())
     }
+
+    /// Saves the index, so that the next `open` only replays what is written after now.
+    fn snapshot(&self) -> Result<()> {
+        Snapshot {
+            gen: self.current_gen,
+            offset: self.writer.pos,
+            uncompacted: self.uncompacted,
+            index: self.index.entries(),
+        }
+        .write(&self.path)
+    }
+}
+
+impl Drop for KvStoreWriter {
+    // The last clone of a `KvStore` is gone, so this is a clean shutdown.
+    fn drop(&mut self) {
+        if let Err(e) = self.snapshot() {
+            error!(
+                "Cannot save index snapshot of {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Options for `KvStore::open_with`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KvStoreOptions {
+    /// Limits on the live data. Entries are measured as the bytes of their log record.
+    pub capacity: Capacity,
+    /// Keep a 64-bit hash per key in memory instead of the key itself.
+    ///
+    /// This takes much less memory with many keys, but every write of an existing key
+    /// reads its old record first, and `scan` visits keys in hash order.
+    pub compact_index: bool,
 }
 
 #[derive(Clone)]
@@ -279,37 +566,88 @@ pub struct KvStore {
     reader: KvStoreReader,
     // writer of the current log
     writer: Arc<Mutex<KvStoreWriter>>,
-    index: Arc<SkipMap<String, CommandPos>>,
+    index: Arc<Index>,
+    evictor: Option<Arc<Mutex<Evictor>>>,
 }
 
 impl KvStore {
     /// Opens a `kvStore` with the given path.
     /// It will create a new directory if the given does not exist.
+    /// Stores written in an older layout are upgraded to the current format.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Opens a `KvStore` like `open`, with the given options.
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
         let path = Arc::new(path.into());
         std::fs::create_dir_all(&*path)?;
+        remove_spools(&path)?;
+        let format = KvStore::detect_format(&*path)?;
+        if let StoreFormat::Versioned(version) = format {
+            if version > FORMAT_VERSION {
+                return Err(KvsError::UnsupportedFormat(version.to_string()));
+            }
+        }
 
-        let mut readers = BTreeMap::new();
-        let index = Arc::new(SkipMap::new());
+        // a compact index reads keys from the log while it is built
+        let reader = KvStoreReader {
+            path: Arc::clone(&path),
+            safe_point: Arc::new(AtomicU64::new(0)),
+            readers: RefCell::new(BTreeMap::new()),
+        };
 
         let gen_list = sorted_gen_list(&path)?;
         let mut uncompacted = 0;
 
-        for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &index)?;
-            readers.insert(gen, reader);
+        // Only the log written after the snapshot is replayed.
+        let mut start = (0, 0);
+        let index = match Snapshot::read(&path, &gen_list, options.compact_index)? {
+            Some(snapshot) => {
+                uncompacted = snapshot.uncompacted;
+                start = (snapshot.gen, snapshot.offset);
+                Index::from_entries(snapshot.index)
+            }
+            None => Index::new(options.compact_index),
+        };
+        let index = Arc::new(index);
+
+        // Parsing dominates startup, so the generations are parsed in parallel.
+        let loaded = gen_list
+            .par_iter()
+            .filter(|&&gen| gen >= start.0)
+            .map(|&gen| -> Result<_> {
+                let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
+                let from = if gen == start.0 { start.1 } else { 0 };
+                let partial = load(gen, &mut reader, from)?.complete()?;
+                Ok((reader, partial))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // later generations win, so they are merged in order
+        for (log_reader, partial) in loaded {
+            reader.readers.borrow_mut().insert(partial.gen, log_reader);
+            uncompacted += partial.merge_into(&index, &reader)?;
         }
 
+        let evictor = if options.capacity.is_unlimited() {
+            None
+        } else {
+            let mut evictor = Evictor::new(options.capacity);
+            for entry in index.keys(&reader) {
+                let (key, cmd_pos) = entry?;
+                // only this policy needs the expiry, which takes a read per key
+                let expires = match options.capacity.policy {
+                    EvictionPolicy::TtlFirst => reader.read_expiry(cmd_pos)?,
+                    _ => None,
+                };
+                evictor.insert(&key, cmd_pos.len, expires);
+            }
+            evictor.loaded();
+            Some(Arc::new(Mutex::new(evictor)))
+        };
+
         let current_gen = gen_list.last().unwrap_or(&0) + 1;
         let writer = new_log_file(&path, current_gen)?;
-        let safe_point = Arc::new(AtomicU64::new(0));
-
-        let reader = KvStoreReader {
-            path: Arc::clone(&path),
-            safe_point,
-            readers: RefCell::new(readers),
-        };
 
         let writer = Arc::new(Mutex::new(KvStoreWriter {
             reader: reader.clone(),
@@ -318,36 +656,189 @@ impl KvStore {
             uncompacted,
             path: Arc::clone(&path),
             index: Arc::clone(&index),
+            publisher: Publisher::default(),
+            evictor: evictor.clone(),
+            degraded: None,
         }));
 
-        Ok(KvStore {
+        let store = KvStore {
             path,
             reader,
             index,
             writer,
-        })
+            evictor,
+        };
+        if format == StoreFormat::Empty {
+            write_format(&store.path)?;
+        } else if format.needs_upgrade() {
+            store.upgrade(format)?;
+        }
+        // the capacity may be smaller than what is already stored
+        store.writer.lock().unwrap().evict();
+        Ok(store)
+    }
+
+    /// Builds a `KvStore` in an empty directory from the given entries.
+    ///
+    /// All entries are written into a single fresh generation with one flush at the
+    /// end, which is much faster than replaying `set`s one at a time.
+    /// If a key appears more than once, the last value wins.
+    ///
+    /// If an entry is an error, what was written so far is removed again, so the
+    /// load can be retried in the same directory.
+    pub fn bulk_load<I>(path: impl Into<PathBuf>, entries: I) -> Result<KvStore>
+    where
+        I: IntoIterator<Item = Result<(String, String)>>,
+    {
+        let path = path.into();
+        let created = !path.exists();
+        std::fs::create_dir_all(&path)?;
+        if !sorted_gen_list(&path)?.is_empty() {
+            return Err(KvsError::DirectoryNotEmpty);
+        }
+
+        let write_log = || -> Result<()> {
+            let mut writer = new_log_file(&path, 1)?;
+            for entry in entries {
+                let (key, value) = entry?;
+                let cmd = Command::Set {
+                    key,
+                    expires: None,
+                    value,
+                };
+                serde_json::to_writer(&mut writer, &cmd)?;
+            }
+            writer.flush()?;
+            writer.writer.get_ref().sync_all()?;
+            Ok(())
+        };
+        if let Err(e) = write_log() {
+            let cleanup = if created {
+                std::fs::remove_dir_all(&path)
+            } else {
+                std::fs::remove_file(log_path(&path, 1))
+            };
+            if let Err(e) = cleanup {
+                error!(
+                    "Cannot clean up {} after a failed load: {}",
+                    path.display(),
+                    e
+                );
+            }
+            return Err(e);
+        }
+        // the directory only looks like a store once the log is complete
+        write_format(&path)?;
+
+        KvStore::open(path)
     }
 }
 
 impl KvsEngine for KvStore {
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)
+        self.writer.lock().unwrap().set(key, value, None)
+    }
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.writer
+            .lock()
+            .unwrap()
+            .set(key, value, Some(expiry(ttl)))
     }
     fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            if let Command::Set { value, .. } = self.reader.read_command(*cmd_pos.value())? {
+        match self.index.read(&key, &self.reader)? {
+            Some(Command::Set { expires, .. }) if is_expired(expires) => Ok(None),
+            Some(Command::Set { value, .. }) => {
+                if let Some(evictor) = &self.evictor {
+                    evictor.lock().unwrap().touch(&key);
+                }
                 Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommandErr)
             }
-        } else {
-            Ok(None)
+            Some(Command::Remove { .. }) => Err(KvsError::UnexpectedCommandErr),
+            None => Ok(None),
         }
     }
 
     fn remove(&self, key: String) -> Result<()> {
         self.writer.lock().unwrap().remove(key)
     }
+
+    fn scan<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        for cmd_pos in self.index.positions() {
+            if let Command::Set {
+                key,
+                expires,
+                value,
+            } = self.reader.read_command(cmd_pos)?
+            {
+                if !is_expired(expires) {
+                    f(key, value)?;
+                }
+            } else {
+                return Err(KvsError::UnexpectedCommandErr);
+            }
+        }
+        Ok(())
+    }
+
+    fn subscribe(&self, prefix: String) -> Result<Subscription> {
+        Ok(self.writer.lock().unwrap().publisher.subscribe(prefix))
+    }
+
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        self.writer.lock().unwrap().incr_by(key, delta)
+    }
+
+    /// Reads the value straight from the log, undoing the escapes of its JSON.
+    fn get_reader(&self, key: String) -> Result<Option<impl Read + Send + 'static>> {
+        let cmd_pos = match self.index.get(&key, &self.reader)? {
+            Some(cmd_pos) => cmd_pos,
+            None => return Ok(None),
+        };
+        // A file of its own, so that the value can be read at the caller's pace.
+        let mut file = File::open(log_path(&self.path, cmd_pos.gen))?;
+        file.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let record = BufReader::new(file).take(cmd_pos.len);
+        let reader = ValueReader::new(record)?;
+        if is_expired(reader.expires()) {
+            return Ok(None);
+        }
+        if let Some(evictor) = &self.evictor {
+            evictor.lock().unwrap().touch(&key);
+        }
+        Ok(Some(reader))
+    }
+
+    /// Receives the value into a spool first, so that other writes only wait while
+    /// it is copied to the log.
+    fn set_from_reader<R: Read>(&self, key: String, len: u64, reader: R) -> Result<()> {
+        let spool = Spool::write(&self.path, &key, len, reader)?;
+        self.writer.lock().unwrap().set_from_spool(key, &spool)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let health = match &self.writer.lock().unwrap().degraded {
+            Some(degraded) => Health::ReadOnly(degraded.cause.clone()),
+            None => Health::Ok,
+        };
+        Ok(EngineStats {
+            keys: Some(self.index.len() as u64),
+            evictions: self
+                .evictor
+                .as_ref()
+                .map_or(0, |evictor| evictor.lock().unwrap().evictions()),
+            health,
+        })
+    }
+
+    fn sync(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.writer.flush()?;
+        writer.writer.writer.get_ref().sync_all()?;
+        Ok(())
+    }
 }
 
 /// Returns sorted generation numbers in the given directory.
@@ -384,36 +875,96 @@ fn log_path(path: &Path, gen: u64) -> PathBuf {
     path.join(format!("{}.log", gen))
 }
 
-/// Load the whole log file and store value locations in the index map.
-/// Returns how many bytes can be saved after a compaction.
-fn load(
+/// The file a compaction writes to before it is renamed to the log of `gen`.
+fn compaction_path(path: &Path, gen: u64) -> PathBuf {
+    path.join(format!("{}.compact", gen))
+}
+
+/// The commands of a single generation, reduced to the last one of every key.
+struct PartialIndex {
     gen: u64,
-    reader: &mut BufReaderWithPos<File>,
-    index: &SkipMap<String, CommandPos>,
-) -> Result<u64> {
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    // `None` if the last command of the key is a remove
+    entries: HashMap<String, Option<CommandPos>>,
+    // bytes made stale by later commands of the same generation
+    uncompacted: u64,
+    sets: u64,
+    removes: u64,
+    // offset after the last command read
+    end: u64,
+    // why reading stopped before the end of the log, if it did
+    error: Option<serde_json::Error>,
+}
+
+impl PartialIndex {
+    /// Fails if a command of the generation could not be read.
+    fn complete(mut self) -> Result<Self> {
+        match self.error.take() {
+            Some(e) => Err(KvsError::CorruptLog {
+                gen: self.gen,
+                offset: self.end,
+                cause: e.to_string(),
+            }),
+            None => Ok(self),
+        }
+    }
+
+    /// Applies the generation on top of the earlier ones already in `index`.
+    /// Returns how many bytes can be saved after a compaction.
+    fn merge_into(self, index: &Index, reader: &KvStoreReader) -> Result<u64> {
+        let mut uncompacted = self.uncompacted;
+        for (key, cmd_pos) in self.entries {
+            let old = match cmd_pos {
+                Some(cmd_pos) => index.insert(key, cmd_pos, reader)?,
+                None => index.remove(&key, reader)?,
+            };
+            uncompacted += old.map_or(0, |old_cmd| old_cmd.len);
+        }
+        Ok(uncompacted)
+    }
+}
+
+/// Load the log file from offset `from` on into a partial index of its generation.
+///
+/// Loading stops at the first command that cannot be read, with the reason in
+/// `PartialIndex::error`.
+fn load(gen: u64, reader: &mut BufReaderWithPos<File>, from: u64) -> Result<PartialIndex> {
+    let mut pos = reader.seek(SeekFrom::Start(from))?;
     let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-    let mut uncompacted = 0;
+    let mut partial = PartialIndex {
+        gen,
+        entries: HashMap::new(),
+        uncompacted: 0,
+        sets: 0,
+        removes: 0,
+        end: pos,
+        error: None,
+    };
     while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
+        let new_pos = from + stream.byte_offset() as u64;
+        let cmd = match cmd {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                partial.error = Some(e);
+                break;
+            }
+        };
+        let (key, cmd_pos) = match cmd {
             Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.get(&key) {
-                    uncompacted += old_cmd.value().len;
-                }
-                index.insert(key, (gen, pos..new_pos).into());
+                partial.sets += 1;
+                (key, Some((gen, pos..new_pos).into()))
             }
-
             Command::Remove { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.value().len;
-                }
+                partial.removes += 1;
                 // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`.
-                uncompacted += new_pos - pos;
+                partial.uncompacted += new_pos - pos;
+                (key, None)
             }
+        };
+        if let Some(Some(old_cmd)) = partial.entries.insert(key, cmd_pos) {
+            partial.uncompacted += old_cmd.len;
         }
         pos = new_pos;
     }
-    Ok(uncompacted)
+    partial.end = pos;
+    Ok(partial)
 }
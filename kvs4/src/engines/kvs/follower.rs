@@ -0,0 +1,156 @@
+use super::{
+    is_expired, load, log_path, sorted_gen_list, BufReaderWithPos, Command, Index, KvStore,
+    KvStoreReader, StoreFormat, FORMAT_VERSION,
+};
+use crate::{KvsError, Result};
+
+use std::cell::RefCell;
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+
+/// A read-only view of a `KvStore` directory that another process writes to.
+///
+/// A follower never modifies the directory, so any number of them can run next to
+/// the process that owns the store. Reads see the store as of the last `refresh`,
+/// which picks up whatever the writer appended since.
+///
+/// Only records the writer has completely flushed are read. A record that is still
+/// being appended is picked up by a later refresh. When a compaction of the writer
+/// replaces generations, the follower rebuilds its index from the files left.
+pub struct KvStoreFollower {
+    path: Arc<PathBuf>,
+    reader: KvStoreReader,
+    index: Index,
+    // how far each generation has been read into the index
+    offsets: BTreeMap<u64, u64>,
+}
+
+impl KvStoreFollower {
+    /// Opens the store at `path` for reading and loads everything written so far.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStoreFollower> {
+        let path = Arc::new(path.into());
+        if let StoreFormat::Versioned(version) = KvStore::detect_format(&*path)? {
+            if version > FORMAT_VERSION {
+                return Err(KvsError::UnsupportedFormat(version.to_string()));
+            }
+        }
+
+        let mut follower = KvStoreFollower {
+            path: Arc::clone(&path),
+            reader: KvStoreReader {
+                path,
+                // the follower drops its file handles itself when it rebuilds
+                safe_point: Arc::new(AtomicU64::new(0)),
+                readers: RefCell::new(BTreeMap::new()),
+            },
+            index: Index::new(false),
+            offsets: BTreeMap::new(),
+        };
+        follower.refresh()?;
+        Ok(follower)
+    }
+
+    /// Reads the records appended since the last refresh into the index.
+    pub fn refresh(&mut self) -> Result<()> {
+        while !self.catch_up()? {
+            self.index = Index::new(false);
+            self.offsets.clear();
+            self.reader.readers.borrow_mut().clear();
+        }
+        Ok(())
+    }
+
+    /// Gets the string value of a given string key, as of the last refresh.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.index.read(&key, &self.reader)? {
+            Some(Command::Set { expires, .. }) if is_expired(expires) => Ok(None),
+            Some(Command::Set { value, .. }) => Ok(Some(value)),
+            Some(Command::Remove { .. }) => Err(KvsError::UnexpectedCommandErr),
+            None => Ok(None),
+        }
+    }
+
+    /// Calls `f` with every key and value as of the last refresh, in key order.
+    pub fn scan<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        for entry in self.index.keys(&self.reader) {
+            let (_, cmd_pos) = entry?;
+            if let Command::Set {
+                key,
+                expires,
+                value,
+            } = self.reader.read_command(cmd_pos)?
+            {
+                if !is_expired(expires) {
+                    f(key, value)?;
+                }
+            } else {
+                return Err(KvsError::UnexpectedCommandErr);
+            }
+        }
+        Ok(())
+    }
+
+    /// Tails every generation in the directory.
+    /// Returns `false` if a compaction got in the way and the index must be rebuilt.
+    fn catch_up(&mut self) -> Result<bool> {
+        let gen_list = sorted_gen_list(&self.path)?;
+        // A compaction starts a new generation for writes first, then adds the
+        // compacted generation below it and removes all older ones.
+        let last_gen = self.offsets.keys().next_back().copied();
+        let removed = self
+            .offsets
+            .keys()
+            .any(|gen| gen_list.binary_search(gen).is_err());
+        let inserted = gen_list
+            .iter()
+            .any(|gen| !self.offsets.contains_key(gen) && Some(*gen) < last_gen);
+        if removed || inserted {
+            return Ok(false);
+        }
+
+        for gen in gen_list {
+            let offset = self.offsets.get(&gen).copied().unwrap_or(0);
+            let offset = match self.tail(gen, offset) {
+                Err(KvsError::IoErr(ref e)) if e.kind() == io::ErrorKind::NotFound => {
+                    return Ok(false)
+                }
+                res => res?,
+            };
+            self.offsets.insert(gen, offset);
+        }
+        Ok(true)
+    }
+
+    /// Applies the complete records of `gen` after `offset` to the index.
+    /// Returns the offset after the last of them.
+    fn tail(&mut self, gen: u64, offset: u64) -> Result<u64> {
+        let mut partial = {
+            let mut readers = self.reader.readers.borrow_mut();
+            // The handle is kept for reads, so the file stays readable on Unix even
+            // after a compaction deletes it.
+            let reader = match readers.entry(gen) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let file = File::open(log_path(&self.path, gen))?;
+                    entry.insert(BufReaderWithPos::new(file)?)
+                }
+            };
+            load(gen, reader, offset)?
+        };
+        // the writer has not finished appending the record after `end` yet
+        if partial.error.as_ref().is_some_and(|e| e.is_eof()) {
+            partial.error = None;
+        }
+        let partial = partial.complete()?;
+        let end = partial.end;
+        partial.merge_into(&self.index, &self.reader)?;
+        Ok(end)
+    }
+}
@@ -0,0 +1,82 @@
+use super::{sorted_gen_list, KvStore};
+use crate::{KvsError, Result};
+
+use log::info;
+
+use std::fs;
+use std::path::Path;
+
+/// Name of the file that records the on-disk format version of a `KvStore`.
+const FORMAT_FILE: &str = "format";
+
+/// The on-disk format version written by this version of `KvStore`.
+pub(super) const FORMAT_VERSION: u32 = 1;
+
+/// On-disk layouts that `KvStore::open` recognizes.
+///
+/// All layouts so far share the same JSON log records, so older layouts are read
+/// as they are and then rewritten into a single generation of the current format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreFormat {
+    /// The directory holds no logs yet
+    Empty,
+    /// Logs written by the single-threaded `kvs` CLI. There is no `engine` marker,
+    /// and every invocation of the CLI left an extra, mostly empty, generation behind.
+    LegacyCli,
+    /// Logs written by a `kvs-server` before format versions were recorded
+    LegacyServer,
+    /// Logs with a recorded format version
+    Versioned(u32),
+}
+
+impl StoreFormat {
+    /// Returns `true` if `open` rewrites the directory into the current format.
+    pub fn needs_upgrade(self) -> bool {
+        match self {
+            StoreFormat::LegacyCli | StoreFormat::LegacyServer => true,
+            StoreFormat::Empty | StoreFormat::Versioned(_) => false,
+        }
+    }
+}
+
+impl KvStore {
+    /// Detects the on-disk layout of the given directory without modifying it.
+    pub fn detect_format(path: impl AsRef<Path>) -> Result<StoreFormat> {
+        let path = path.as_ref();
+        let format_file = path.join(FORMAT_FILE);
+        if format_file.exists() {
+            let content = fs::read_to_string(&format_file)?;
+            return match content.trim().parse() {
+                Ok(version) => Ok(StoreFormat::Versioned(version)),
+                Err(_) => Err(KvsError::UnsupportedFormat(content)),
+            };
+        }
+
+        if !path.exists() || sorted_gen_list(path)?.is_empty() {
+            Ok(StoreFormat::Empty)
+        } else if path.join("engine").exists() {
+            Ok(StoreFormat::LegacyServer)
+        } else {
+            Ok(StoreFormat::LegacyCli)
+        }
+    }
+
+    /// Rewrites all live data into a single generation and records the current format.
+    pub(super) fn upgrade(&self, from: StoreFormat) -> Result<()> {
+        self.writer.lock().unwrap().compact()?;
+        write_format(&self.path)?;
+        info!(
+            "Upgraded {:?} store at {} to format version {}",
+            from,
+            self.path.display(),
+            FORMAT_VERSION
+        );
+        Ok(())
+    }
+}
+
+/// Records the current format version in the given directory.
+pub(super) fn write_format(path: &Path) -> Result<()> {
+    fs::write(path.join(FORMAT_FILE), format!("{}\n", FORMAT_VERSION))?;
+    Ok(())
+}
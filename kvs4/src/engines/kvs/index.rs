@@ -0,0 +1,250 @@
+use super::{Command, CommandPos, KvStoreReader};
+use crate::engines::fnv1a;
+use crate::{KvsError, Result};
+
+use crossbeam_skiplist::SkipMap;
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryFrom;
+
+const FNV_BASIS: u64 = 0x6c62_272e_07bb_0142;
+
+/// The hash of a key, and a number that tells apart keys with the same hash.
+type Slot = (u64, u32);
+
+/// A `CommandPos` in 16 instead of 24 bytes.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub(super) struct PackedPos {
+    pos: u64,
+    gen: u32,
+    len: u32,
+}
+
+impl TryFrom<CommandPos> for PackedPos {
+    type Error = KvsError;
+
+    fn try_from(cmd_pos: CommandPos) -> Result<Self> {
+        match (u32::try_from(cmd_pos.gen), u32::try_from(cmd_pos.len)) {
+            (Ok(gen), Ok(len)) => Ok(PackedPos {
+                pos: cmd_pos.pos,
+                gen,
+                len,
+            }),
+            _ => Err(KvsError::StringErr(format!(
+                "Record of {} bytes in generation {} does not fit the compact index",
+                cmd_pos.len, cmd_pos.gen
+            ))),
+        }
+    }
+}
+
+impl From<PackedPos> for CommandPos {
+    fn from(packed: PackedPos) -> Self {
+        CommandPos {
+            gen: u64::from(packed.gen),
+            pos: packed.pos,
+            len: u64::from(packed.len),
+        }
+    }
+}
+
+/// Where the index finds the latest record of every live key.
+pub(super) enum Index {
+    /// Every key in memory, in key order
+    Full(SkipMap<String, CommandPos>),
+    /// A 64-bit hash per key, in hash order.
+    ///
+    /// Keys are read back from the log to resolve hashes, so writes of existing keys
+    /// read a record before they append one.
+    Compact(SkipMap<Slot, PackedPos>),
+}
+
+/// The entries of an `Index`, as saved in a snapshot.
+#[derive(Serialize, Deserialize)]
+pub(super) enum IndexEntries {
+    Full(Vec<(String, CommandPos)>),
+    Compact(Vec<(Slot, PackedPos)>),
+}
+
+impl Index {
+    pub fn new(compact: bool) -> Index {
+        if compact {
+            Index::Compact(SkipMap::new())
+        } else {
+            Index::Full(SkipMap::new())
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Index::Full(map) => map.len(),
+            Index::Compact(map) => map.len(),
+        }
+    }
+
+    /// Returns the position of the latest record of `key`.
+    pub fn get(&self, key: &str, reader: &KvStoreReader) -> Result<Option<CommandPos>> {
+        match self {
+            Index::Full(map) => Ok(map.get(key).map(|entry| *entry.value())),
+            Index::Compact(map) => Ok(find(map, key, reader)?.map(|(_, cmd_pos, _)| cmd_pos)),
+        }
+    }
+
+    /// Returns the latest record of `key`.
+    pub fn read(&self, key: &str, reader: &KvStoreReader) -> Result<Option<Command>> {
+        match self {
+            Index::Full(map) => match map.get(key) {
+                Some(entry) => Ok(Some(reader.read_command(*entry.value())?)),
+                None => Ok(None),
+            },
+            // resolving the hash already reads the record
+            Index::Compact(map) => Ok(find(map, key, reader)?.map(|(_, _, cmd)| cmd)),
+        }
+    }
+
+    /// Points `key` to `cmd_pos` and returns where it pointed before.
+    pub fn insert(
+        &self,
+        key: String,
+        cmd_pos: CommandPos,
+        reader: &KvStoreReader,
+    ) -> Result<Option<CommandPos>> {
+        match self {
+            Index::Full(map) => {
+                let old = map.get(&key).map(|entry| *entry.value());
+                map.insert(key, cmd_pos);
+                Ok(old)
+            }
+            Index::Compact(map) => {
+                let packed = PackedPos::try_from(cmd_pos)?;
+                if let Some((slot, old, _)) = find(map, &key, reader)? {
+                    map.insert(slot, packed);
+                    return Ok(Some(old));
+                }
+                let hash = hash(&key);
+                let number = map
+                    .range((hash, 0)..=(hash, u32::MAX))
+                    .next_back()
+                    .map_or(0, |entry| entry.key().1 + 1);
+                map.insert((hash, number), packed);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Removes `key` and returns where it pointed.
+    pub fn remove(&self, key: &str, reader: &KvStoreReader) -> Result<Option<CommandPos>> {
+        match self {
+            Index::Full(map) => Ok(map.remove(key).map(|entry| *entry.value())),
+            Index::Compact(map) => match find(map, key, reader)? {
+                Some((slot, old, _)) => {
+                    map.remove(&slot);
+                    Ok(Some(old))
+                }
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// Returns the positions of all records, in index order.
+    pub fn positions(&self) -> Box<dyn Iterator<Item = CommandPos> + '_> {
+        match self {
+            Index::Full(map) => Box::new(map.iter().map(|entry| *entry.value())),
+            Index::Compact(map) => Box::new(map.iter().map(|entry| (*entry.value()).into())),
+        }
+    }
+
+    /// Moves the records to `positions`, given in the order of `positions()`.
+    pub fn set_positions(&self, positions: Vec<CommandPos>) -> Result<()> {
+        match self {
+            Index::Full(map) => {
+                for (entry, cmd_pos) in map.iter().zip(positions) {
+                    map.insert(entry.key().clone(), cmd_pos);
+                }
+            }
+            Index::Compact(map) => {
+                for (entry, cmd_pos) in map.iter().zip(positions) {
+                    map.insert(*entry.key(), PackedPos::try_from(cmd_pos)?);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns every key with its position. Keys of a compact index are read from the log.
+    pub fn keys<'a>(
+        &'a self,
+        reader: &'a KvStoreReader,
+    ) -> Box<dyn Iterator<Item = Result<(String, CommandPos)>> + 'a> {
+        match self {
+            Index::Full(map) => Box::new(
+                map.iter()
+                    .map(|entry| Ok((entry.key().clone(), *entry.value()))),
+            ),
+            Index::Compact(map) => Box::new(map.iter().map(move |entry| {
+                let cmd_pos = (*entry.value()).into();
+                let key = reader.read_command(cmd_pos)?.into_key();
+                Ok((key, cmd_pos))
+            })),
+        }
+    }
+
+    pub fn entries(&self) -> IndexEntries {
+        match self {
+            Index::Full(map) => IndexEntries::Full(
+                map.iter()
+                    .map(|entry| (entry.key().clone(), *entry.value()))
+                    .collect(),
+            ),
+            Index::Compact(map) => IndexEntries::Compact(
+                map.iter()
+                    .map(|entry| (*entry.key(), *entry.value()))
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn from_entries(entries: IndexEntries) -> Index {
+        match entries {
+            IndexEntries::Full(entries) => Index::Full(entries.into_iter().collect()),
+            IndexEntries::Compact(entries) => Index::Compact(entries.into_iter().collect()),
+        }
+    }
+}
+
+impl IndexEntries {
+    pub fn is_compact(&self) -> bool {
+        matches!(self, IndexEntries::Compact(_))
+    }
+
+    /// Returns the generations the entries point to.
+    pub fn gens(&self) -> Box<dyn Iterator<Item = u64> + '_> {
+        match self {
+            IndexEntries::Full(entries) => Box::new(entries.iter().map(|(_, cmd_pos)| cmd_pos.gen)),
+            IndexEntries::Compact(entries) => {
+                Box::new(entries.iter().map(|(_, packed)| u64::from(packed.gen)))
+            }
+        }
+    }
+}
+
+fn hash(key: &str) -> u64 {
+    fnv1a(key.as_bytes(), FNV_BASIS)
+}
+
+/// Looks up `key` among the keys with the same hash, by reading their records.
+fn find(
+    map: &SkipMap<Slot, PackedPos>,
+    key: &str,
+    reader: &KvStoreReader,
+) -> Result<Option<(Slot, CommandPos, Command)>> {
+    let hash = hash(key);
+    for entry in map.range((hash, 0)..=(hash, u32::MAX)) {
+        let cmd_pos = (*entry.value()).into();
+        let cmd = reader.read_command(cmd_pos)?;
+        if cmd.key() == key {
+            return Ok(Some((*entry.key(), cmd_pos, cmd)));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,107 @@
+use super::index::IndexEntries;
+use super::log_path;
+use crate::engines::fnv1a;
+use crate::Result;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Name of the file holding the index snapshot.
+const SNAPSHOT_FILE: &str = "index.snapshot";
+
+const FNV_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// The index of a `KvStore` as of a point in its log, so that `open` only has to
+/// replay the log written after it.
+///
+/// On disk the JSON is followed by an 8-byte little-endian FNV-1a checksum of it.
+#[derive(Serialize, Deserialize)]
+pub(super) struct Snapshot {
+    /// The generation written to when the snapshot was taken
+    pub gen: u64,
+    /// How much of `gen` the index covers
+    pub offset: u64,
+    /// Stale bytes in the generations up to `gen`
+    pub uncompacted: u64,
+    pub index: IndexEntries,
+}
+
+impl Snapshot {
+    /// Replaces the snapshot in `path` with this one.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut bytes = serde_json::to_vec(self)?;
+        let checksum = fnv1a(&bytes, FNV_BASIS);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+
+        // like a compaction, the snapshot only replaces the old one once it is complete
+        let tmp_path = path.join(format!("{}.tmp", SNAPSHOT_FILE));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path.join(SNAPSHOT_FILE))?;
+        Ok(())
+    }
+
+    /// Reads the snapshot in `path`, unless there is none, it is damaged, it is of
+    /// the other kind of index, or the logs in `gen_list` no longer contain what it
+    /// refers to.
+    pub fn read(path: &Path, gen_list: &[u64], compact: bool) -> Result<Option<Snapshot>> {
+        let bytes = match fs::read(path.join(SNAPSHOT_FILE)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let snapshot = match Snapshot::decode(&bytes) {
+            Some(snapshot) => snapshot,
+            None => {
+                warn!("Ignoring damaged index snapshot in {}", path.display());
+                return Ok(None);
+            }
+        };
+        if snapshot.index.is_compact() != compact {
+            return Ok(None);
+        }
+        if !snapshot.matches(path, gen_list)? {
+            warn!("Ignoring outdated index snapshot in {}", path.display());
+            return Ok(None);
+        }
+        Ok(Some(snapshot))
+    }
+
+    /// Deletes the snapshot in `path`, if any.
+    pub fn remove(path: &Path) -> Result<()> {
+        match fs::remove_file(path.join(SNAPSHOT_FILE)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Snapshot> {
+        if bytes.len() < 8 {
+            return None;
+        }
+        let (body, checksum) = bytes.split_at(bytes.len() - 8);
+        if fnv1a(body, FNV_BASIS) != u64::from_le_bytes(checksum.try_into().unwrap()) {
+            return None;
+        }
+        serde_json::from_slice(body).ok()
+    }
+
+    fn matches(&self, path: &Path, gen_list: &[u64]) -> Result<bool> {
+        // Compactions and repairs remove the generation that was current, so it is
+        // enough to check that it is still there and has not been cut short.
+        if gen_list.binary_search(&self.gen).is_err()
+            || fs::metadata(log_path(path, self.gen))?.len() < self.offset
+        {
+            return Ok(false);
+        }
+        Ok(self
+            .index
+            .gens()
+            .all(|gen| gen_list.binary_search(&gen).is_ok()))
+    }
+}
@@ -0,0 +1,280 @@
+use crate::common::Utf8Chunks;
+use crate::{KvsError, Result};
+use log::error;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// tells apart the spools of values that are received at the same time
+static NEXT_SPOOL: AtomicU64 = AtomicU64::new(0);
+
+/// Writes the `len` bytes of `reader` to `writer` as a JSON string.
+///
+/// Problems with the value itself, like invalid UTF-8 or a `reader` that ends early,
+/// are reported as `KvsError::InvalidValue`. That way they can be told apart from
+/// failed writes.
+fn write_value<R: Read, W: Write>(reader: R, len: u64, writer: &mut W) -> Result<()> {
+    let mut read = 0;
+    writer.write_all(b"\"")?;
+    for chunk in Utf8Chunks::new(reader.take(len)) {
+        let chunk = chunk.map_err(|e| KvsError::InvalidValue(e.to_string()))?;
+        read += chunk.len() as u64;
+        escape(&chunk, writer)?;
+    }
+    if read < len {
+        return Err(KvsError::InvalidValue(format!(
+            "expected {} bytes, got {}",
+            len, read
+        )));
+    }
+    writer.write_all(b"\"")?;
+    Ok(())
+}
+
+/// A `Set` record written to a file of its own in the store directory, so that a
+/// value can be received from a slow client before the log is locked.
+///
+/// The file is removed when the spool is dropped. Spools left behind by a crash
+/// are removed by `remove_spools`.
+pub(super) struct Spool {
+    path: PathBuf,
+    file: File,
+}
+
+impl Spool {
+    /// Writes the `Set` record of `key` with the `len` bytes of `reader` as value.
+    pub(super) fn write<R: Read>(dir: &Path, key: &str, len: u64, reader: R) -> Result<Spool> {
+        let id = NEXT_SPOOL.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("{}.spool", id));
+        let spool = Spool {
+            file: OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)?,
+            path,
+        };
+        let mut writer = BufWriter::new(&spool.file);
+        // the same JSON as a `Command::Set`
+        writer.write_all(b"{\"Set\":{\"key\":")?;
+        serde_json::to_writer(&mut writer, key)?;
+        writer.write_all(b",\"value\":")?;
+        write_value(reader, len, &mut writer)?;
+        writer.write_all(b"}}")?;
+        writer.flush()?;
+        drop(writer);
+        Ok(spool)
+    }
+
+    /// Copies the record to `writer`.
+    pub(super) fn copy_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(0))?;
+        io::copy(&mut BufReader::new(file), writer)?;
+        Ok(())
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            error!("Cannot remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Removes the spools a crash left in `dir`.
+pub(super) fn remove_spools(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some("spool".as_ref()) {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `s` with the same escapes as `serde_json`.
+fn escape<W: Write>(s: &str, writer: &mut W) -> Result<()> {
+    let bytes = s.as_bytes();
+    let mut start = 0;
+    for (i, &byte) in bytes.iter().enumerate() {
+        let escaped: &[u8] = match byte {
+            b'"' => b"\\\"",
+            b'\\' => b"\\\\",
+            b'\n' => b"\\n",
+            b'\r' => b"\\r",
+            b'\t' => b"\\t",
+            0x08 => b"\\b",
+            0x0c => b"\\f",
+            0x00..=0x1f => {
+                writer.write_all(&bytes[start..i])?;
+                write!(writer, "\\u{:04x}", byte)?;
+                start = i + 1;
+                continue;
+            }
+            _ => continue,
+        };
+        writer.write_all(&bytes[start..i])?;
+        writer.write_all(escaped)?;
+        start = i + 1;
+    }
+    writer.write_all(&bytes[start..])?;
+    Ok(())
+}
+
+/// Reads the value out of a `Set` record, undoing the escapes of its JSON string.
+pub(super) struct ValueReader<R: BufRead> {
+    reader: R,
+    expires: Option<u64>,
+    // decoded bytes of an escape that did not fit the caller's buffer
+    pending: Vec<u8>,
+    done: bool,
+}
+
+impl<R: BufRead> ValueReader<R> {
+    /// Skips the record up to the value. Fails if it is not a `Set`.
+    pub(super) fn new(mut reader: R) -> Result<Self> {
+        expect(&mut reader, b"{\"Set\":{\"key\":\"")?;
+        // skip the key
+        loop {
+            match next_byte(&mut reader)? {
+                b'"' => break,
+                b'\\' => {
+                    next_byte(&mut reader)?;
+                }
+                _ => {}
+            }
+        }
+        expect(&mut reader, b",\"")?;
+        // the expiry sits between the key and the value, if there is one
+        let expires = if reader.fill_buf()?.first() == Some(&b'e') {
+            expect(&mut reader, b"expires\":")?;
+            let mut expires: u64 = 0;
+            loop {
+                let digit = match next_byte(&mut reader)? {
+                    b',' => break,
+                    byte @ b'0'..=b'9' => u64::from(byte - b'0'),
+                    _ => return Err(KvsError::UnexpectedCommandErr),
+                };
+                expires = expires
+                    .checked_mul(10)
+                    .and_then(|expires| expires.checked_add(digit))
+                    .ok_or(KvsError::UnexpectedCommandErr)?;
+            }
+            expect(&mut reader, b"\"")?;
+            Some(expires)
+        } else {
+            None
+        };
+        expect(&mut reader, b"value\":\"")?;
+        Ok(ValueReader {
+            reader,
+            expires,
+            pending: Vec::new(),
+            done: false,
+        })
+    }
+
+    /// When the key expires, in milliseconds since the Unix epoch.
+    pub(super) fn expires(&self) -> Option<u64> {
+        self.expires
+    }
+
+    /// Decodes the escape after a backslash into `pending`.
+    fn unescape(&mut self) -> io::Result<()> {
+        let byte = match next_byte(&mut self.reader)? {
+            b'b' => 0x08,
+            b'f' => 0x0c,
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'u' => {
+                let mut code = u32::from(read_hex(&mut self.reader)?);
+                if (0xd800..0xdc00).contains(&code) {
+                    // the first half of a surrogate pair
+                    if next_byte(&mut self.reader)? != b'\\' || next_byte(&mut self.reader)? != b'u'
+                    {
+                        return Err(invalid("unpaired surrogate"));
+                    }
+                    let low = u32::from(read_hex(&mut self.reader)?);
+                    if !(0xdc00..0xe000).contains(&low) {
+                        return Err(invalid("unpaired surrogate"));
+                    }
+                    code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                }
+                let c = char::from_u32(code).ok_or_else(|| invalid("invalid escape"))?;
+                self.pending
+                    .extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                return Ok(());
+            }
+            byte => byte,
+        };
+        self.pending.push(byte);
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for ValueReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() && !self.done && !buf.is_empty() {
+            let available = self.reader.fill_buf()?;
+            match available.first() {
+                None => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Some(b'"') => {
+                    self.reader.consume(1);
+                    self.done = true;
+                }
+                Some(b'\\') => {
+                    self.reader.consume(1);
+                    self.unescape()?;
+                }
+                Some(_) => {
+                    let run = available
+                        .iter()
+                        .take(buf.len())
+                        .position(|&byte| byte == b'"' || byte == b'\\')
+                        .unwrap_or_else(|| available.len().min(buf.len()));
+                    buf[..run].copy_from_slice(&available[..run]);
+                    self.reader.consume(run);
+                    return Ok(run);
+                }
+            }
+        }
+        let n = self.pending.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+fn next_byte<R: BufRead>(reader: &mut R) -> io::Result<u8> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_hex<R: BufRead>(reader: &mut R) -> io::Result<u16> {
+    let mut digits = [0; 4];
+    reader.read_exact(&mut digits)?;
+    std::str::from_utf8(&digits)
+        .ok()
+        .and_then(|digits| u16::from_str_radix(digits, 16).ok())
+        .ok_or_else(|| invalid("invalid escape"))
+}
+
+fn expect<R: BufRead>(reader: &mut R, expected: &[u8]) -> Result<()> {
+    let mut actual = vec![0; expected.len()];
+    reader.read_exact(&mut actual)?;
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(KvsError::UnexpectedCommandErr)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
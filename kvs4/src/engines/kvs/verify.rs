@@ -0,0 +1,211 @@
+use super::{
+    load, log_path, sorted_gen_list, BufReaderWithPos, Index, KvStore, KvStoreReader, Snapshot,
+    StoreFormat,
+};
+use crate::Result;
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+
+/// Name of the directory that repairs move unreadable files into.
+const QUARANTINE_DIR: &str = "quarantine";
+
+/// What `KvStore::verify` found in a single generation.
+#[derive(Debug)]
+pub struct GenerationReport {
+    /// The generation number
+    pub gen: u64,
+    /// Size of the log file in bytes
+    pub len: u64,
+    /// Number of `Set` records read before any corruption
+    pub sets: u64,
+    /// Number of `Remove` records read before any corruption
+    pub removes: u64,
+    /// Offset of the first record that cannot be read.
+    /// Everything before it is intact.
+    pub corrupt_offset: Option<u64>,
+    /// Why reading stopped at `corrupt_offset`
+    pub error: Option<String>,
+}
+
+impl GenerationReport {
+    /// Number of intact records in the generation.
+    pub fn records(&self) -> u64 {
+        self.sets + self.removes
+    }
+}
+
+/// What `KvStore::verify` found in a data directory.
+#[derive(Debug)]
+pub struct VerifyReport {
+    /// The detected on-disk layout
+    pub format: StoreFormat,
+    /// One report per `N.log` file, in generation order
+    pub generations: Vec<GenerationReport>,
+    /// Leftover `.compact` files of compactions that never finished
+    pub orphan_compactions: Vec<PathBuf>,
+    /// Number of keys in the index rebuilt from the intact records
+    pub live_keys: u64,
+    /// Bytes of stale records that a compaction would reclaim
+    pub uncompacted: u64,
+}
+
+impl VerifyReport {
+    /// Returns `true` if `KvStore::open` can load the directory as it is.
+    pub fn is_clean(&self) -> bool {
+        self.orphan_compactions.is_empty()
+            && self.generations.iter().all(|g| g.corrupt_offset.is_none())
+    }
+}
+
+/// What `KvStore::repair` changed in a data directory.
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// Generations cut back to their last intact record, with their new length
+    pub truncated: Vec<(u64, u64)>,
+    /// Generations without a single readable record, moved to `quarantine/`
+    pub quarantined: Vec<u64>,
+    /// Orphan compaction files, moved to `quarantine/`
+    pub orphans_removed: Vec<PathBuf>,
+    /// The clean generation all live data was compacted into
+    pub compaction_gen: u64,
+}
+
+impl KvStore {
+    /// Walks every generation in `path` the same way `open` does, without modifying
+    /// anything, and reports what it finds.
+    pub fn verify(path: impl AsRef<Path>) -> Result<VerifyReport> {
+        let path = path.as_ref();
+        let format = KvStore::detect_format(path)?;
+        // a full index never reads from the logs
+        let reader = KvStoreReader {
+            path: Arc::new(path.to_owned()),
+            safe_point: Arc::new(AtomicU64::new(0)),
+            readers: RefCell::new(BTreeMap::new()),
+        };
+        let index = Index::new(false);
+        let mut uncompacted = 0;
+        let mut generations = Vec::new();
+        for gen in sorted_gen_list(path)? {
+            let (report, stale) = verify_gen(path, gen, &index, &reader)?;
+            uncompacted += stale;
+            generations.push(report);
+        }
+
+        Ok(VerifyReport {
+            format,
+            generations,
+            orphan_compactions: orphan_compactions(path)?,
+            live_keys: index.len() as u64,
+            uncompacted,
+        })
+    }
+
+    /// Makes a data directory loadable again.
+    ///
+    /// Corrupt tails are cut off and saved next to unreadable generations and orphan
+    /// compaction files in a `quarantine` directory. Then all live data is compacted
+    /// into a single clean generation.
+    pub fn repair(path: impl AsRef<Path>) -> Result<RepairReport> {
+        let path = path.as_ref();
+        let report = KvStore::verify(path)?;
+        let quarantine = path.join(QUARANTINE_DIR);
+        let mut repair = RepairReport::default();
+        // the repair changes the logs under the snapshot
+        Snapshot::remove(path)?;
+
+        for orphan in report.orphan_compactions {
+            fs::create_dir_all(&quarantine)?;
+            fs::rename(&orphan, quarantine.join(orphan.file_name().unwrap()))?;
+            repair.orphans_removed.push(orphan);
+        }
+
+        for gen in report.generations {
+            match gen.corrupt_offset {
+                None => {}
+                Some(0) => {
+                    fs::create_dir_all(&quarantine)?;
+                    fs::rename(
+                        log_path(path, gen.gen),
+                        quarantine.join(format!("{}.log", gen.gen)),
+                    )?;
+                    repair.quarantined.push(gen.gen);
+                }
+                Some(offset) => {
+                    fs::create_dir_all(&quarantine)?;
+                    let mut file = OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .open(log_path(path, gen.gen))?;
+                    // keep the unreadable tail around for inspection
+                    file.seek(SeekFrom::Start(offset))?;
+                    let mut tail = File::create(quarantine.join(format!("{}.tail", gen.gen)))?;
+                    io::copy(&mut file, &mut tail)?;
+                    file.set_len(offset)?;
+                    file.sync_all()?;
+                    repair.truncated.push((gen.gen, offset));
+                }
+            }
+        }
+
+        let store = KvStore::open(path)?;
+        let mut writer = store.writer.lock().unwrap();
+        writer.compact()?;
+        // `compact` moves the writer two generations ahead of the compaction file
+        repair.compaction_gen = writer.current_gen - 1;
+        Ok(repair)
+    }
+}
+
+/// Loads a single generation into `index` the way `open` does, stopping at the first
+/// unreadable record. Returns the report and how many bytes became stale.
+fn verify_gen(
+    path: &Path,
+    gen: u64,
+    index: &Index,
+    reader: &KvStoreReader,
+) -> Result<(GenerationReport, u64)> {
+    let file_path = log_path(path, gen);
+    let mut report = GenerationReport {
+        gen,
+        len: fs::metadata(&file_path)?.len(),
+        sets: 0,
+        removes: 0,
+        corrupt_offset: None,
+        error: None,
+    };
+    let file = match File::open(&file_path) {
+        Ok(file) => file,
+        Err(e) => {
+            report.corrupt_offset = Some(0);
+            report.error = Some(e.to_string());
+            return Ok((report, 0));
+        }
+    };
+
+    let mut partial = load(gen, &mut BufReaderWithPos::new(file)?, 0)?;
+    report.sets = partial.sets;
+    report.removes = partial.removes;
+    if let Some(e) = partial.error.take() {
+        report.corrupt_offset = Some(partial.end);
+        report.error = Some(e.to_string());
+    }
+    let uncompacted = partial.merge_into(index, reader)?;
+    Ok((report, uncompacted))
+}
+
+/// Returns the `.compact` files in the given directory.
+fn orphan_compactions(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut orphans: Vec<PathBuf> = fs::read_dir(path)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some(OsStr::new("compact")))
+        .collect();
+    orphans.sort();
+    Ok(orphans)
+}
@@ -0,0 +1,479 @@
+use super::{parse_integer, EngineStats, KvsEngine, Publisher, Subscription};
+use crate::{KvsError, Result};
+
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex, RwLock};
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+mod bloom;
+mod sstable;
+
+use self::sstable::{Merge, Table};
+
+/// The memtable is flushed into a new SSTable once the write-ahead log grows past this size.
+const MEMTABLE_THRESHOLD: u64 = 1024 * 1024;
+
+/// Compaction merges the newest tables once this many of them share a size tier.
+const TIER_FANOUT: usize = 4;
+
+/// Name of the write-ahead log that backs the memtable.
+const WAL_FILE: &str = "wal";
+
+/// A record of the write-ahead log and of SSTables
+#[derive(Serialize, Deserialize, Debug)]
+enum Command {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl Command {
+    fn from_entry(key: String, value: Option<String>) -> Command {
+        match value {
+            Some(value) => Command::Set { key, value },
+            None => Command::Remove { key },
+        }
+    }
+
+    fn into_entry(self) -> (String, Option<String>) {
+        match self {
+            Command::Set { key, value } => (key, Some(value)),
+            Command::Remove { key } => (key, None),
+        }
+    }
+
+    fn key(&self) -> &str {
+        match self {
+            Command::Set { key, .. } | Command::Remove { key } => key,
+        }
+    }
+}
+
+/// Writes that are not in an SSTable yet. A `None` value is a tombstone that
+/// hides the key in older tables.
+type Memtable = BTreeMap<String, Option<String>>;
+
+/// Everything a read has to look at. Only the writer changes it.
+struct Levels {
+    memtable: Memtable,
+    // oldest first
+    tables: Vec<Arc<Table>>,
+}
+
+/// A single thread reader of SSTables.
+/// Like `KvStoreReader`, every `LsmStore` clone opens the table files separately.
+struct LsmReader {
+    path: Arc<PathBuf>,
+    readers: RefCell<BTreeMap<u64, BufReader<File>>>,
+}
+
+impl LsmReader {
+    /// Looks `key` up in the memtable, then in the tables from newest to oldest.
+    fn get(&self, levels: &Levels, key: &str) -> Result<Option<String>> {
+        if let Some(value) = levels.memtable.get(key) {
+            return Ok(value.clone());
+        }
+
+        let mut readers = self.readers.borrow_mut();
+        for table in levels.tables.iter().rev() {
+            if !readers.contains_key(&table.gen) {
+                // a new table usually means others were compacted away, so close those
+                readers.retain(|gen, _| levels.tables.iter().any(|table| table.gen == *gen));
+                let reader = BufReader::new(File::open(table_path(&self.path, table.gen))?);
+                readers.insert(table.gen, reader);
+            }
+            if let Some(value) = table.get(readers.get_mut(&table.gen).unwrap(), key)? {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Clone for LsmReader {
+    fn clone(&self) -> Self {
+        LsmReader {
+            path: Arc::clone(&self.path),
+            // don't share file handles with the other reader
+            readers: RefCell::new(BTreeMap::new()),
+        }
+    }
+}
+
+struct LsmWriter {
+    reader: LsmReader,
+    wal: BufWriter<File>,
+    // bytes in the write-ahead log
+    wal_len: u64,
+    next_gen: u64,
+    path: Arc<PathBuf>,
+    levels: Arc<RwLock<Levels>>,
+    publisher: Publisher,
+}
+
+impl LsmWriter {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.reader.get(&self.levels.read().unwrap(), key)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.write(Command::Set { key, value })
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.get(&key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.write(Command::Remove { key })
+    }
+
+    fn incr_by(&mut self, key: String, delta: i64) -> Result<i64> {
+        let current = match self.get(&key)? {
+            Some(value) => parse_integer(&key, &value)?,
+            None => 0,
+        };
+        let new = current
+            .checked_add(delta)
+            .ok_or(KvsError::IntegerOverflow)?;
+        self.set(key, new.to_string())?;
+        Ok(new)
+    }
+
+    /// Logs the command, then applies it to the memtable.
+    fn write(&mut self, cmd: Command) -> Result<()> {
+        let buf = serde_json::to_vec(&cmd)?;
+        self.wal.write_all(&buf)?;
+        self.wal.flush()?;
+        self.wal_len += buf.len() as u64;
+
+        match &cmd {
+            Command::Set { key, value } => self.publisher.publish_set(key, value),
+            Command::Remove { key } => self.publisher.publish_remove(key),
+        }
+        let (key, value) = cmd.into_entry();
+        self.levels.write().unwrap().memtable.insert(key, value);
+
+        if self.wal_len > MEMTABLE_THRESHOLD {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the memtable into a new SSTable and empties the write-ahead log.
+    fn flush(&mut self) -> Result<()> {
+        let gen = self.next_gen;
+        self.next_gen += 1;
+        let table = {
+            // reads go on while the table is written
+            let levels = self.levels.read().unwrap();
+            let records = levels
+                .memtable
+                .iter()
+                .map(|(key, value)| Ok(Command::from_entry(key.clone(), value.clone())));
+            Table::write(&self.path, gen, gen, levels.memtable.len() as u64, records)?
+        };
+
+        let mut levels = self.levels.write().unwrap();
+        levels.tables.push(Arc::new(table));
+        levels.memtable.clear();
+        drop(levels);
+
+        // Everything in the log is in the table now. A crash before this point
+        // replays the log into the memtable again, which is harmless.
+        self.wal.get_ref().set_len(0)?;
+        self.wal_len = 0;
+        self.compact()
+    }
+
+    /// Size-tiered compaction: merges the newest tables into one whenever
+    /// `TIER_FANOUT` of them have the same size tier.
+    fn compact(&mut self) -> Result<()> {
+        loop {
+            let tables = self.levels.read().unwrap().tables.clone();
+            let start = match full_tier(&tables) {
+                Some(start) => start,
+                None => return Ok(()),
+            };
+            let inputs = &tables[start..];
+
+            let sources = inputs
+                .iter()
+                .rev()
+                .map(
+                    |table| -> Result<Box<dyn Iterator<Item = Result<Command>>>> {
+                        Ok(Box::new(table.iter(&self.path)?))
+                    },
+                )
+                .collect::<Result<_>>()?;
+            // tombstones only hide keys in older tables, so they are dropped once
+            // the oldest table takes part in the merge
+            let keep_tombstones = start > 0;
+            let records = Merge::new(sources)?
+                .filter(|cmd| keep_tombstones || !matches!(cmd, Ok(Command::Remove { .. })));
+            let gen = self.next_gen;
+            self.next_gen += 1;
+            let capacity = inputs.iter().map(|table| table.entries).sum();
+            // The inputs are obsolete once the new table is in place, even if the
+            // process dies before they are deleted. Otherwise an input left over with
+            // a dropped tombstone would bring back the key.
+            let table = Table::write(&self.path, gen, inputs[0].gen, capacity, records)?;
+
+            let mut levels = self.levels.write().unwrap();
+            levels.tables.truncate(start);
+            levels.tables.push(Arc::new(table));
+            drop(levels);
+
+            // Readers that still have the old files open keep reading them until they
+            // notice the new table. See `KvStoreWriter::compact` for Windows.
+            for table in inputs {
+                let file_path = table_path(&self.path, table.gen);
+                if let Err(e) = fs::remove_file(&file_path) {
+                    warn!("{:?} cannot be deleted: {}", file_path, e);
+                }
+            }
+        }
+    }
+}
+
+/// A log-structured merge-tree engine.
+///
+/// Writes go to a write-ahead log and an in-memory memtable, which is flushed into
+/// a sorted, immutable SSTable once it grows large. Only a sparse index and a bloom
+/// filter of every table are kept in memory, so the key count is not bounded by RAM.
+#[derive(Clone)]
+pub struct LsmStore {
+    reader: LsmReader,
+    writer: Arc<Mutex<LsmWriter>>,
+    levels: Arc<RwLock<Levels>>,
+}
+
+impl LsmStore {
+    /// Opens a `LsmStore` with the given path.
+    /// It will create a new directory if the given does not exist.
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmStore> {
+        let path = Arc::new(path.into());
+        fs::create_dir_all(&*path)?;
+
+        let mut tables = Vec::new();
+        for gen in sorted_table_list(&path)? {
+            tables.push(Arc::new(Table::open(&path, gen)?));
+        }
+        remove_temp_files(&path)?;
+        remove_replaced_tables(&path, &mut tables)?;
+        let next_gen = tables.last().map_or(1, |table| table.gen + 1);
+
+        let (memtable, wal_len) = replay_wal(&path)?;
+        let wal = BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path.join(WAL_FILE))?,
+        );
+
+        let levels = Arc::new(RwLock::new(Levels { memtable, tables }));
+        let reader = LsmReader {
+            path: Arc::clone(&path),
+            readers: RefCell::new(BTreeMap::new()),
+        };
+        let writer = Arc::new(Mutex::new(LsmWriter {
+            reader: reader.clone(),
+            wal,
+            wal_len,
+            next_gen,
+            path,
+            levels: Arc::clone(&levels),
+            publisher: Publisher::default(),
+        }));
+
+        Ok(LsmStore {
+            reader,
+            writer,
+            levels,
+        })
+    }
+}
+
+impl KvsEngine for LsmStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.writer.lock().unwrap().set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.reader.get(&self.levels.read().unwrap(), &key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.writer.lock().unwrap().remove(key)
+    }
+
+    fn scan<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        // Work on a snapshot, so `f` does not run under the lock.
+        // The table files are opened before compaction can remove them.
+        let sources = {
+            let levels = self.levels.read().unwrap();
+            let memtable = levels.memtable.clone().into_iter();
+            let mut sources: Vec<Box<dyn Iterator<Item = Result<Command>>>> = vec![Box::new(
+                memtable.map(|(key, value)| Ok(Command::from_entry(key, value))),
+            )];
+            for table in levels.tables.iter().rev() {
+                sources.push(Box::new(table.iter(&self.reader.path)?));
+            }
+            sources
+        };
+
+        for cmd in Merge::new(sources)? {
+            if let Command::Set { key, value } = cmd? {
+                f(key, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn subscribe(&self, prefix: String) -> Result<Subscription> {
+        Ok(self.writer.lock().unwrap().publisher.subscribe(prefix))
+    }
+
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        self.writer.lock().unwrap().incr_by(key, delta)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        // counting keys would mean merging every table
+        Ok(EngineStats::default())
+    }
+
+    /// Syncs the write-ahead log. The tables are synced when they are written.
+    fn sync(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.wal.flush()?;
+        writer.wal.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+/// Returns where the run of newest tables that share a size tier starts,
+/// if the run is long enough to be merged.
+fn full_tier(tables: &[Arc<Table>]) -> Option<usize> {
+    let newest = tier(tables.last()?.len);
+    let run = tables
+        .iter()
+        .rev()
+        .take_while(|table| tier(table.len) == newest)
+        .count();
+    if run >= TIER_FANOUT {
+        Some(tables.len() - run)
+    } else {
+        None
+    }
+}
+
+/// Tier 0 holds tables of up to twice the memtable threshold, which is about
+/// what a flush writes. Each following tier holds `TIER_FANOUT` times larger tables.
+fn tier(len: u64) -> u32 {
+    let mut tier = 0;
+    let mut limit = 2 * MEMTABLE_THRESHOLD;
+    while len > limit {
+        tier += 1;
+        limit *= TIER_FANOUT as u64;
+    }
+    tier
+}
+
+/// Rebuilds the memtable from the write-ahead log and returns it with the length
+/// of the log. A torn record at the end, left by a crash in the middle of a write,
+/// is cut off.
+fn replay_wal(path: &Path) -> Result<(Memtable, u64)> {
+    let wal_path = path.join(WAL_FILE);
+    let mut memtable = Memtable::new();
+    if !wal_path.exists() {
+        return Ok((memtable, 0));
+    }
+
+    let mut pos = 0;
+    let mut stream =
+        Deserializer::from_reader(BufReader::new(File::open(&wal_path)?)).into_iter::<Command>();
+    while let Some(cmd) = stream.next() {
+        match cmd {
+            Ok(cmd) => {
+                pos = stream.byte_offset() as u64;
+                let (key, value) = cmd.into_entry();
+                memtable.insert(key, value);
+            }
+            Err(e) => {
+                warn!("Cutting off the write-ahead log at offset {}: {}", pos, e);
+                OpenOptions::new()
+                    .write(true)
+                    .open(&wal_path)?
+                    .set_len(pos)?;
+                break;
+            }
+        }
+    }
+    Ok((memtable, pos))
+}
+
+/// Returns sorted generation numbers of the SSTables in the given directory.
+fn sorted_table_list(path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = fs::read_dir(path)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some("sst".as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .map(|s| s.trim_end_matches(".sst"))
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    gen_list.sort_unstable();
+    Ok(gen_list)
+}
+
+/// Removes the inputs of compactions that were not deleted before the process died.
+fn remove_replaced_tables(path: &Path, tables: &mut Vec<Arc<Table>>) -> Result<()> {
+    let replaced: Vec<u64> = tables
+        .iter()
+        .map(|table| table.gen)
+        .filter(|&gen| {
+            tables
+                .iter()
+                .any(|table| table.replaces <= gen && gen < table.gen)
+        })
+        .collect();
+    for &gen in &replaced {
+        fs::remove_file(table_path(path, gen))?;
+    }
+    tables.retain(|table| !replaced.contains(&table.gen));
+    Ok(())
+}
+
+/// Removes the unfinished tables of flushes and compactions that never completed.
+fn remove_temp_files(path: &Path) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let file_path = entry?.path();
+        if file_path.is_file() && file_path.extension() == Some("tmp".as_ref()) {
+            fs::remove_file(&file_path)?;
+        }
+    }
+    Ok(())
+}
+
+fn table_path(path: &Path, gen: u64) -> PathBuf {
+    path.join(format!("{}.sst", gen))
+}
+
+/// The file a table is written to before it is renamed to `table_path`.
+fn temp_path(path: &Path, gen: u64) -> PathBuf {
+    path.join(format!("{}.tmp", gen))
+}
@@ -0,0 +1,45 @@
+use crate::engines::fnv1a;
+use serde::{Deserialize, Serialize};
+
+/// Bits reserved per key. With `HASHES` probes this gives about 1% false positives.
+const BITS_PER_KEY: u64 = 10;
+const HASHES: u64 = 7;
+
+/// A bloom filter over the keys of one SSTable.
+///
+/// The hashes are persisted with the table, so they must not change between
+/// builds. That rules out `DefaultHasher`; FNV-1a is used instead.
+#[derive(Serialize, Deserialize)]
+pub(super) struct Bloom {
+    bits: Vec<u64>,
+}
+
+impl Bloom {
+    /// Creates an empty filter sized for `keys` keys.
+    pub fn with_capacity(keys: u64) -> Self {
+        let words = (keys * BITS_PER_KEY).div_ceil(64);
+        Bloom {
+            bits: vec![0; words.max(1) as usize],
+        }
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        for bit in self.probes(key) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Returns `false` if `key` was never inserted.
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.probes(key)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    fn probes(&self, key: &str) -> impl Iterator<Item = u64> {
+        // double hashing: the i-th probe is h1 + i * h2
+        let h1 = fnv1a(key.as_bytes(), 0xcbf2_9ce4_8422_2325);
+        let h2 = fnv1a(key.as_bytes(), 0x8422_2325_cbf2_9ce4) | 1;
+        let len = self.bits.len() as u64 * 64;
+        (0..HASHES).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % len)
+    }
+}
@@ -0,0 +1,231 @@
+use super::bloom::Bloom;
+use super::{table_path, temp_path, Command};
+use crate::{KvsError, Result};
+
+use serde_json::de::IoRead;
+use serde_json::{Deserializer, StreamDeserializer};
+
+use std::cmp::Ordering;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Take, Write};
+use std::path::Path;
+
+/// Every `INDEX_INTERVAL`th record of a table is kept in its sparse index.
+const INDEX_INTERVAL: u64 = 16;
+
+/// The footer holds the offsets of the index and of the bloom filter, the number
+/// of records and the oldest generation the table replaces, as little-endian `u64`s.
+const FOOTER_LEN: u64 = 32;
+
+/// An immutable, sorted run of records on disk.
+///
+/// A table file holds the JSON records in key order, followed by the sparse index,
+/// the bloom filter and the footer. Only the index and the filter are kept in memory.
+pub(super) struct Table {
+    pub gen: u64,
+    /// Size of the table file in bytes
+    pub len: u64,
+    /// Number of records, including tombstones
+    pub entries: u64,
+    /// A table written by a compaction replaces the tables from this generation up
+    /// to its own. Otherwise this is its own generation.
+    pub replaces: u64,
+    // first key and offset of every block of `INDEX_INTERVAL` records
+    index: Vec<(String, u64)>,
+    // the records end where the index starts
+    data_end: u64,
+    bloom: Bloom,
+}
+
+impl Table {
+    /// Reads the index and the bloom filter of table `gen`.
+    pub fn open(dir: &Path, gen: u64) -> Result<Table> {
+        let corrupt = |cause: String| KvsError::CorruptTable { gen, cause };
+        let mut file = File::open(table_path(dir, gen))?;
+        let len = file.metadata()?.len();
+        if len < FOOTER_LEN {
+            return Err(corrupt("missing footer".to_owned()));
+        }
+
+        let mut footer = [0; FOOTER_LEN as usize];
+        file.seek(SeekFrom::Start(len - FOOTER_LEN))?;
+        file.read_exact(&mut footer)?;
+        let field = |i: usize| u64::from_le_bytes(footer[i * 8..i * 8 + 8].try_into().unwrap());
+        let (index_offset, bloom_offset, entries, replaces) =
+            (field(0), field(1), field(2), field(3));
+        if index_offset > bloom_offset || bloom_offset > len - FOOTER_LEN || replaces > gen {
+            return Err(corrupt("invalid footer".to_owned()));
+        }
+
+        file.seek(SeekFrom::Start(index_offset))?;
+        let mut reader = BufReader::new(file);
+        let index = serde_json::from_reader((&mut reader).take(bloom_offset - index_offset))
+            .map_err(|e| corrupt(e.to_string()))?;
+        let bloom = serde_json::from_reader(reader.take(len - FOOTER_LEN - bloom_offset))
+            .map_err(|e| corrupt(e.to_string()))?;
+        Ok(Table {
+            gen,
+            len,
+            entries,
+            replaces,
+            index,
+            data_end: index_offset,
+            bloom,
+        })
+    }
+
+    /// Writes `records`, which must be sorted by key, to a new table `gen`.
+    ///
+    /// `capacity` is the expected number of records and sizes the bloom filter.
+    /// The table is written to a temporary file first, so it only shows up in the
+    /// directory once it is complete. Tables from generation `replaces` up to `gen`
+    /// are obsolete from then on.
+    pub fn write<I>(dir: &Path, gen: u64, replaces: u64, capacity: u64, records: I) -> Result<Table>
+    where
+        I: IntoIterator<Item = Result<Command>>,
+    {
+        let temp = temp_path(dir, gen);
+        let mut writer = BufWriter::new(File::create(&temp)?);
+        let mut index = Vec::new();
+        let mut bloom = Bloom::with_capacity(capacity);
+        let mut entries = 0;
+        let mut pos = 0;
+        for cmd in records {
+            let cmd = cmd?;
+            if entries % INDEX_INTERVAL == 0 {
+                index.push((cmd.key().to_owned(), pos));
+            }
+            bloom.insert(cmd.key());
+            pos += write_json(&mut writer, &cmd)?;
+            entries += 1;
+        }
+
+        let index_offset = pos;
+        let bloom_offset = index_offset + write_json(&mut writer, &index)?;
+        let len = bloom_offset + write_json(&mut writer, &bloom)? + FOOTER_LEN;
+        for field in &[index_offset, bloom_offset, entries, replaces] {
+            writer.write_all(&field.to_le_bytes())?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+        fs::rename(&temp, table_path(dir, gen))?;
+
+        Ok(Table {
+            gen,
+            len,
+            entries,
+            replaces,
+            index,
+            data_end: index_offset,
+            bloom,
+        })
+    }
+
+    /// Looks `key` up through the given reader of the table file.
+    /// Returns `None` if the table knows nothing about `key`, and `Some(None)` if it
+    /// holds a tombstone for it.
+    pub fn get(&self, reader: &mut BufReader<File>, key: &str) -> Result<Option<Option<String>>> {
+        if !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        // the last block starting at or before `key`
+        let block = match self
+            .index
+            .partition_point(|(first, _)| first.as_str() <= key)
+        {
+            0 => return Ok(None),
+            i => i - 1,
+        };
+        let start = self.index[block].1;
+        let end = self
+            .index
+            .get(block + 1)
+            .map_or(self.data_end, |(_, pos)| *pos);
+
+        reader.seek(SeekFrom::Start(start))?;
+        let stream = Deserializer::from_reader(reader.take(end - start)).into_iter::<Command>();
+        for cmd in stream {
+            let cmd = cmd?;
+            match cmd.key().cmp(key) {
+                Ordering::Less => continue,
+                Ordering::Equal => return Ok(Some(cmd.into_entry().1)),
+                Ordering::Greater => break,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Iterates over all records of the table in key order through a new file handle.
+    pub fn iter(&self, dir: &Path) -> Result<TableIter> {
+        let file = BufReader::new(File::open(table_path(dir, self.gen))?);
+        Ok(TableIter {
+            stream: Deserializer::from_reader(file.take(self.data_end)).into_iter(),
+        })
+    }
+}
+
+pub(super) struct TableIter {
+    stream: StreamDeserializer<'static, IoRead<Take<BufReader<File>>>, Command>,
+}
+
+impl Iterator for TableIter {
+    type Item = Result<Command>;
+
+    fn next(&mut self) -> Option<Result<Command>> {
+        self.stream.next().map(|cmd| Ok(cmd?))
+    }
+}
+
+/// Merges sorted runs of records into one sorted run.
+/// When several runs hold the same key, the record of the earliest run wins,
+/// so runs are passed newest first.
+pub(super) struct Merge {
+    sources: Vec<Box<dyn Iterator<Item = Result<Command>>>>,
+    // the next record of each source
+    heads: Vec<Option<Command>>,
+}
+
+impl Merge {
+    pub fn new(mut sources: Vec<Box<dyn Iterator<Item = Result<Command>>>>) -> Result<Merge> {
+        let heads = sources
+            .iter_mut()
+            .map(|source| source.next().transpose())
+            .collect::<Result<_>>()?;
+        Ok(Merge { sources, heads })
+    }
+}
+
+impl Iterator for Merge {
+    type Item = Result<Command>;
+
+    fn next(&mut self) -> Option<Result<Command>> {
+        // `min_by` returns the first of equal keys, which is the newest record
+        let (first, _) = self
+            .heads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, head)| head.as_ref().map(|cmd| (i, cmd.key())))
+            .min_by(|a, b| a.1.cmp(b.1))?;
+        let cmd = self.heads[first].take().unwrap();
+
+        // advance every source past the key, dropping the older records
+        for i in first..self.heads.len() {
+            if i == first || self.heads[i].as_ref().map(Command::key) == Some(cmd.key()) {
+                match self.sources[i].next().transpose() {
+                    Ok(head) => self.heads[i] = head,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+        }
+        Some(Ok(cmd))
+    }
+}
+
+/// Writes `value` as JSON and returns the number of bytes written.
+fn write_json<W: Write, T: serde::Serialize>(writer: &mut W, value: &T) -> Result<u64> {
+    let buf = serde_json::to_vec(value)?;
+    writer.write_all(&buf)?;
+    Ok(buf.len() as u64)
+}
@@ -0,0 +1,183 @@
+use super::{
+    expiry, is_expired, parse_integer, Capacity, EngineStats, Evictor, Health, KvsEngine,
+    Publisher, Subscription,
+};
+use crate::{KvsError, Result};
+
+use crossbeam_skiplist::SkipMap;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// An engine that keeps everything in memory and persists nothing.
+///
+/// Useful as a cache and in tests. With a `Capacity` keys are evicted by its policy,
+/// and subscribers see evictions as removes. Entries are measured as the bytes of
+/// their key and value.
+#[derive(Clone)]
+pub struct MemStore {
+    map: Arc<SkipMap<String, Stored>>,
+    writer: Arc<Mutex<MemStoreWriter>>,
+    // only present with a capacity, so unbounded stores read without locking
+    evictor: Option<Arc<Mutex<Evictor>>>,
+}
+
+/// A value and when it expires, in milliseconds since the Unix epoch.
+struct Stored {
+    value: String,
+    expires: Option<u64>,
+}
+
+impl Stored {
+    /// Returns the value unless it has expired.
+    fn live(&self) -> Option<&String> {
+        Some(&self.value).filter(|_| !is_expired(self.expires))
+    }
+}
+
+struct MemStoreWriter {
+    map: Arc<SkipMap<String, Stored>>,
+    evictor: Option<Arc<Mutex<Evictor>>>,
+    publisher: Publisher,
+}
+
+impl MemStoreWriter {
+    fn set(&mut self, key: String, value: String, expires: Option<u64>) -> Result<()> {
+        self.publisher.publish_set(&key, &value);
+        let size = (key.len() + value.len()) as u64;
+        let value = Stored { value, expires };
+        if let Some(evictor) = &self.evictor {
+            let mut evictor = evictor.lock().unwrap();
+            evictor.insert(&key, size, expires);
+            self.map.insert(key, value);
+            for victim in evictor.victims() {
+                self.map.remove(&victim);
+                self.publisher.publish_remove(&victim);
+            }
+        } else {
+            self.map.insert(key, value);
+        }
+        Ok(())
+    }
+
+    /// Removes `key`. An expired key is removed all the same, but reported as
+    /// missing, since it was already.
+    fn remove(&mut self, key: String) -> Result<()> {
+        let entry = self.map.remove(&key).ok_or(KvsError::KeyNotFound)?;
+        if let Some(evictor) = &self.evictor {
+            evictor.lock().unwrap().remove(&key);
+        }
+        self.publisher.publish_remove(&key);
+        match entry.value().live() {
+            Some(_) => Ok(()),
+            None => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    fn incr_by(&mut self, key: String, delta: i64) -> Result<i64> {
+        let current = match self.map.get(&key) {
+            Some(entry) => match entry.value().live() {
+                Some(value) => parse_integer(&key, value)?,
+                None => 0,
+            },
+            None => 0,
+        };
+        let new = current
+            .checked_add(delta)
+            .ok_or(KvsError::IntegerOverflow)?;
+        self.set(key, new.to_string(), None)?;
+        Ok(new)
+    }
+}
+
+impl MemStore {
+    /// Creates an empty, unbounded `MemStore`.
+    pub fn new() -> MemStore {
+        MemStore::with_capacity(Capacity::default())
+    }
+
+    /// Creates an empty `MemStore` that evicts keys to stay within `capacity`.
+    pub fn with_capacity(capacity: Capacity) -> MemStore {
+        let map = Arc::new(SkipMap::new());
+        let evictor = if capacity.is_unlimited() {
+            None
+        } else {
+            Some(Arc::new(Mutex::new(Evictor::new(capacity))))
+        };
+        let writer = Arc::new(Mutex::new(MemStoreWriter {
+            map: Arc::clone(&map),
+            evictor: evictor.clone(),
+            publisher: Publisher::default(),
+        }));
+        MemStore {
+            map,
+            writer,
+            evictor,
+        }
+    }
+}
+
+impl Default for MemStore {
+    fn default() -> Self {
+        MemStore::new()
+    }
+}
+
+impl KvsEngine for MemStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.writer.lock().unwrap().set(key, value, None)
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.writer
+            .lock()
+            .unwrap()
+            .set(key, value, Some(expiry(ttl)))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let value = self
+            .map
+            .get(&key)
+            .and_then(|entry| entry.value().live().cloned());
+        if let (Some(_), Some(evictor)) = (&value, &self.evictor) {
+            evictor.lock().unwrap().touch(&key);
+        }
+        Ok(value)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.writer.lock().unwrap().remove(key)
+    }
+
+    fn scan<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        for entry in self.map.iter() {
+            if let Some(value) = entry.value().live() {
+                f(entry.key().clone(), value.clone())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn subscribe(&self, prefix: String) -> Result<Subscription> {
+        Ok(self.writer.lock().unwrap().publisher.subscribe(prefix))
+    }
+
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        self.writer.lock().unwrap().incr_by(key, delta)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            keys: Some(self.map.len() as u64),
+            evictions: self
+                .evictor
+                .as_ref()
+                .map_or(0, |evictor| evictor.lock().unwrap().evictions()),
+            health: Health::Ok,
+        })
+    }
+}
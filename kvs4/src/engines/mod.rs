@@ -1,13 +1,136 @@
-use crate::Result;
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{Cursor, Read};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub trait KvsEngine: Clone + Send + 'static {
     fn set(&self, key: String, value: String) -> Result<()>;
+    /// Sets `key` to `value` until `ttl` has passed. After that the key reads as
+    /// missing, and the `TtlFirst` eviction policy gives it up first.
+    ///
+    /// Any later write of the key without a TTL, including `incr_by`, keeps it for
+    /// good. The default fails, for engines that cannot keep an expiry.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let _ = (key, value, ttl);
+        Err(KvsError::Unsupported("TTL".to_owned()))
+    }
     fn get(&self, key: String) -> Result<Option<String>>;
     fn remove(&self, key: String) -> Result<()>;
+    /// Calls `f` with every live key/value pair in the store.
+    /// Stops at the first error returned by `f`.
+    fn scan<F>(&self, f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>;
+    /// Returns a stream of all later mutations of keys starting with `prefix`.
+    fn subscribe(&self, prefix: String) -> Result<Subscription>;
+    /// Atomically adds `delta` to the integer stored at `key` and returns the result.
+    /// A missing key counts as zero.
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64>;
+    /// Atomically subtracts `delta` from the integer stored at `key` and returns the result.
+    fn decr_by(&self, key: String, delta: i64) -> Result<i64> {
+        let delta = delta.checked_neg().ok_or(KvsError::IntegerOverflow)?;
+        self.incr_by(key, delta)
+    }
+    /// Returns counters describing the store.
+    fn stats(&self) -> Result<EngineStats>;
+    /// Writes buffered data out and waits until it is on disk.
+    /// The default does nothing, for engines that keep nothing on disk.
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+    /// Returns a reader of the value of `key`, for values too large to hold in memory.
+    /// The default reads the whole value with `get`.
+    fn get_reader(&self, key: String) -> Result<Option<impl Read + Send + 'static>> {
+        Ok(self.get(key)?.map(|value| Cursor::new(value.into_bytes())))
+    }
+    /// Sets `key` to the next `len` bytes of `reader`, which must be UTF-8.
+    /// The default reads the whole value into memory and calls `set`.
+    fn set_from_reader<R: Read>(&self, key: String, len: u64, reader: R) -> Result<()> {
+        let mut value = String::new();
+        reader
+            .take(len)
+            .read_to_string(&mut value)
+            .map_err(|e| KvsError::InvalidValue(e.to_string()))?;
+        if (value.len() as u64) < len {
+            return Err(KvsError::InvalidValue(format!(
+                "expected {} bytes, got {}",
+                len,
+                value.len()
+            )));
+        }
+        self.set(key, value)
+    }
 }
 
+/// Counters every engine reports through `KvsEngine::stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// Number of live keys, if the engine can tell without reading all its data
+    pub keys: Option<u64>,
+    /// Number of keys evicted to stay within the capacity since the store was opened
+    pub evictions: u64,
+    pub health: Health,
+}
+
+/// Whether an engine accepts writes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Health {
+    #[default]
+    Ok,
+    /// Writes failed, e.g. because the disk is full. Reads are still served, and
+    /// writes are retried from time to time. Holds the cause of the failure.
+    ReadOnly(String),
+}
+
+/// Parses a stored value for `incr_by`.
+fn parse_integer(key: &str, value: &str) -> Result<i64> {
+    value
+        .parse()
+        .map_err(|_| KvsError::NotAnInteger(key.to_owned()))
+}
+
+/// Returns when a key written now with `ttl` expires, in milliseconds since the Unix
+/// epoch.
+fn expiry(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis().try_into().unwrap_or(u64::MAX))
+}
+
+/// Returns `true` if a key with the expiry `expires` must read as missing by now.
+fn is_expired(expires: Option<u64>) -> bool {
+    expires.is_some_and(|expires| expires <= now_millis())
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as u64)
+}
+
+/// FNV-1a hash of `bytes`, starting from `basis`.
+///
+/// Unlike `DefaultHasher` it is stable across builds, so it can be persisted.
+pub(crate) fn fnv1a(bytes: &[u8], basis: u64) -> u64 {
+    bytes.iter().fold(basis, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+mod capacity;
 mod kvs;
+mod lsm;
+mod mem;
 mod sled;
+mod watch;
 
-pub use self::kvs::KvStore;
+pub use self::capacity::{Capacity, EvictionPolicy};
+pub use self::kvs::{
+    GenerationReport, KvStore, KvStoreFollower, KvStoreOptions, RepairReport, StoreFormat,
+    VerifyReport,
+};
+pub use self::lsm::LsmStore;
+pub use self::mem::MemStore;
 pub use self::sled::SledStore;
+pub use self::watch::{Event, Subscription};
+
+pub(crate) use self::capacity::Evictor;
+pub(crate) use self::watch::Publisher;
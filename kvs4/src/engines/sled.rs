@@ -1,40 +1,222 @@
-use super::KvsEngine;
+use super::{
+    expiry, is_expired, parse_integer, Capacity, EngineStats, Evictor, Health, KvsEngine,
+    Subscription,
+};
 use crate::{KvsError, Result};
+use log::error;
 use sled::{Db, Tree};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Starts a value stored with an expiry, followed by the expiry in milliseconds
+/// since the Unix epoch and the value. No UTF-8 string starts with it, so values
+/// without an expiry are stored as they are.
+const EXPIRY_TAG: u8 = 0xff;
+
+fn encode_value(value: String, expires: Option<u64>) -> Vec<u8> {
+    match expires {
+        Some(expires) => {
+            let mut bytes = Vec::with_capacity(9 + value.len());
+            bytes.push(EXPIRY_TAG);
+            bytes.extend_from_slice(&expires.to_be_bytes());
+            bytes.extend_from_slice(value.as_bytes());
+            bytes
+        }
+        None => value.into_bytes(),
+    }
+}
+
+/// Splits a stored value into its expiry and the value itself.
+pub(crate) fn decode_value(bytes: &[u8]) -> (Option<u64>, &[u8]) {
+    match bytes.split_first() {
+        Some((&EXPIRY_TAG, rest)) if rest.len() >= 8 => {
+            let (expires, value) = rest.split_at(8);
+            (Some(u64::from_be_bytes(expires.try_into().unwrap())), value)
+        }
+        _ => (None, bytes),
+    }
+}
+
+/// Returns the value of the stored `bytes` unless it has expired.
+fn live_value(bytes: &[u8]) -> Option<&[u8]> {
+    let (expires, value) = decode_value(bytes);
+    Some(value).filter(|_| !is_expired(expires))
+}
 
 #[derive(Clone)]
 pub struct SledStore {
     db: Db,
+    evictor: Option<Arc<Mutex<Evictor>>>,
 }
 
 impl SledStore {
     pub fn new(db: Db) -> Self {
-        SledStore { db }
+        SledStore { db, evictor: None }
     }
-}
 
-impl KvsEngine for SledStore {
-    fn set(&self, key: String, value: String) -> Result<()> {
+    /// Wraps `db` and evicts keys to stay within `capacity`.
+    /// Entries are measured as the bytes of their key and value.
+    pub fn with_capacity(db: Db, capacity: Capacity) -> Result<Self> {
+        if capacity.is_unlimited() {
+            return Ok(SledStore::new(db));
+        }
+        let mut evictor = Evictor::new(capacity);
+        for item in db.iter() {
+            let (key, value) = item?;
+            let (expires, value) = decode_value(&value);
+            evictor.insert(
+                &String::from_utf8(key.to_vec())?,
+                (key.len() + value.len()) as u64,
+                expires,
+            );
+        }
+        evictor.loaded();
+        let store = SledStore { db, evictor: None };
+        // the capacity may be smaller than what is already stored
+        store.evict(&mut evictor);
+        store.db.flush()?;
+        Ok(SledStore {
+            evictor: Some(Arc::new(Mutex::new(evictor))),
+            ..store
+        })
+    }
+
+    /// Removes the keys the evictor picks to get back within the capacity.
+    ///
+    /// The write that took the store over capacity has succeeded by then, so a
+    /// failed remove is only logged. The evictor keeps the key and picks it again
+    /// after the next write.
+    fn evict(&self, evictor: &mut Evictor) {
         let tree: &Tree = &self.db;
-        tree.insert(key, value.into_bytes()).map(|_| ())?;
+        while let Some(victim) = evictor.next_victim() {
+            match tree.remove(victim.as_bytes()) {
+                Ok(Some(_)) => evictor.evicted(&victim),
+                Ok(None) => evictor.remove(&victim),
+                Err(e) => {
+                    error!("Cannot evict {}: {}", victim, e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn write(&self, key: String, value: String, expires: Option<u64>) -> Result<()> {
+        let tree: &Tree = &self.db;
+        match &self.evictor {
+            // the evictor lock keeps the tree and the eviction order in step
+            Some(evictor) => {
+                let mut evictor = evictor.lock().unwrap();
+                let size = (key.len() + value.len()) as u64;
+                tree.insert(key.as_bytes(), encode_value(value, expires))?;
+                evictor.insert(&key, size, expires);
+                self.evict(&mut evictor);
+            }
+            None => {
+                tree.insert(key, encode_value(value, expires))?;
+            }
+        }
         self.db.flush()?;
         Ok(())
     }
+}
+
+impl KvsEngine for SledStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.write(key, value, None)
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.write(key, value, Some(expiry(ttl)))
+    }
 
     fn get(&self, key: String) -> Result<Option<String>> {
         let tree: &Tree = &self.db;
         let r = tree
-            .get(key)?
-            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
+            .get(&key)?
+            .and_then(|i_vec| live_value(&i_vec).map(<[u8]>::to_vec))
             .map(String::from_utf8)
             .transpose()?;
+        if let (Some(_), Some(evictor)) = (&r, &self.evictor) {
+            evictor.lock().unwrap().touch(&key);
+        }
         Ok(r)
     }
 
+    /// Removes `key`. An expired key is removed all the same, but reported as
+    /// missing, since it was already.
     fn remove(&self, key: String) -> Result<()> {
         let tree: &Tree = &self.db;
-        tree.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+        let evictor = self.evictor.as_ref().map(|evictor| evictor.lock().unwrap());
+        let old = tree.remove(&key)?.ok_or(KvsError::KeyNotFound)?;
+        if let Some(mut evictor) = evictor {
+            evictor.remove(&key);
+        }
         tree.flush()?;
+        live_value(&old).map(drop).ok_or(KvsError::KeyNotFound)
+    }
+
+    fn scan<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String) -> Result<()>,
+    {
+        for item in self.db.iter() {
+            let (key, value) = item?;
+            if let Some(value) = live_value(&value) {
+                f(
+                    String::from_utf8(key.to_vec())?,
+                    String::from_utf8(value.to_vec())?,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn subscribe(&self, prefix: String) -> Result<Subscription> {
+        Ok(Subscription::from_sled(self.db.watch_prefix(prefix)))
+    }
+
+    fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        let tree: &Tree = &self.db;
+        // The closure may run several times if it races with other writers,
+        // so only the outcome of the last run counts.
+        let mut outcome = Ok(0);
+        tree.update_and_fetch(key.as_bytes(), |old| {
+            // an expired value counts as missing
+            outcome = old
+                .and_then(live_value)
+                .map_or(Ok(0), |bytes| {
+                    parse_integer(&key, &String::from_utf8_lossy(bytes))
+                })
+                .and_then(|current| current.checked_add(delta).ok_or(KvsError::IntegerOverflow));
+            match &outcome {
+                Ok(new) => Some(new.to_string().into_bytes()),
+                // leave the value untouched
+                Err(_) => old.map(<[u8]>::to_vec),
+            }
+        })?;
+        let new = outcome?;
+        if let Some(evictor) = &self.evictor {
+            let mut evictor = evictor.lock().unwrap();
+            evictor.insert(&key, (key.len() + new.to_string().len()) as u64, None);
+            self.evict(&mut evictor);
+        }
+        tree.flush()?;
+        Ok(new)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            keys: Some(self.db.len() as u64),
+            evictions: self
+                .evictor
+                .as_ref()
+                .map_or(0, |evictor| evictor.lock().unwrap().evictions()),
+            health: Health::Ok,
+        })
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.db.flush()?;
         Ok(())
     }
 }
@@ -0,0 +1,202 @@
+use super::sled::decode_value;
+use crossbeam::channel::{self, Receiver, Sender};
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+/// How many events a subscriber may fall behind before it is dropped.
+const SUBSCRIBER_BACKLOG: usize = 4096;
+
+/// A mutation observed through `KvsEngine::subscribe`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Event {
+    Set {
+        seq: u64,
+        key: String,
+        value: String,
+    },
+    Remove {
+        seq: u64,
+        key: String,
+    },
+}
+
+impl Event {
+    /// The sequence number of the mutation. Later mutations have larger numbers.
+    pub fn seq(&self) -> u64 {
+        match self {
+            Event::Set { seq, .. } | Event::Remove { seq, .. } => *seq,
+        }
+    }
+
+    /// The key that was changed.
+    pub fn key(&self) -> &str {
+        match self {
+            Event::Set { key, .. } | Event::Remove { key, .. } => key,
+        }
+    }
+}
+
+/// A blocking stream of the mutations of keys with a given prefix.
+///
+/// The stream ends when the engine is dropped, or when the subscriber falls too far
+/// behind the writers.
+pub struct Subscription {
+    inner: SubscriptionInner,
+}
+
+enum SubscriptionInner {
+    Channel {
+        receiver: Receiver<Event>,
+        // lets the publisher notice the subscription is gone without sending to it
+        _alive: Arc<()>,
+    },
+    Sled {
+        subscriber: sled::Subscriber,
+        // sled does not number its events, so they are numbered per subscription
+        next_seq: u64,
+    },
+}
+
+impl Subscription {
+    pub(crate) fn from_sled(subscriber: sled::Subscriber) -> Self {
+        Subscription {
+            inner: SubscriptionInner::Sled {
+                subscriber,
+                next_seq: 1,
+            },
+        }
+    }
+}
+
+impl Subscription {
+    /// Waits up to `timeout` for the next event.
+    /// Fails with `RecvTimeoutError::Disconnected` once the stream has ended.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Event, RecvTimeoutError> {
+        match &mut self.inner {
+            SubscriptionInner::Channel { receiver, .. } => {
+                receiver.recv_timeout(timeout).map_err(|e| match e {
+                    channel::RecvTimeoutError::Timeout => RecvTimeoutError::Timeout,
+                    channel::RecvTimeoutError::Disconnected => RecvTimeoutError::Disconnected,
+                })
+            }
+            SubscriptionInner::Sled {
+                subscriber,
+                next_seq,
+            } => {
+                let event = subscriber.next_timeout(timeout)?;
+                let seq = *next_seq;
+                *next_seq += 1;
+                Ok(from_sled_event(seq, event))
+            }
+        }
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        match &mut self.inner {
+            SubscriptionInner::Channel { receiver, .. } => receiver.recv().ok(),
+            SubscriptionInner::Sled {
+                subscriber,
+                next_seq,
+            } => {
+                let event = subscriber.next()?;
+                let seq = *next_seq;
+                *next_seq += 1;
+                Some(from_sled_event(seq, event))
+            }
+        }
+    }
+}
+
+fn from_sled_event(seq: u64, event: sled::Event) -> Event {
+    match event {
+        sled::Event::Insert { key, value } => Event::Set {
+            seq,
+            key: String::from_utf8_lossy(&key).into_owned(),
+            value: String::from_utf8_lossy(decode_value(&value).1).into_owned(),
+        },
+        sled::Event::Remove { key } => Event::Remove {
+            seq,
+            key: String::from_utf8_lossy(&key).into_owned(),
+        },
+    }
+}
+
+/// Numbers the mutations of an engine and fans them out to subscribers.
+/// Engines call it while holding their write lock so events are published in order.
+#[derive(Default)]
+pub(crate) struct Publisher {
+    seq: u64,
+    subscribers: Vec<Subscriber>,
+}
+
+struct Subscriber {
+    prefix: String,
+    sender: Sender<Event>,
+    // dead once the `Subscription` is dropped
+    alive: Weak<()>,
+}
+
+impl Publisher {
+    pub fn subscribe(&mut self, prefix: String) -> Subscription {
+        let (sender, receiver) = channel::bounded(SUBSCRIBER_BACKLOG);
+        let alive = Arc::new(());
+        self.subscribers
+            .retain(|subscriber| subscriber.alive.strong_count() > 0);
+        self.subscribers.push(Subscriber {
+            prefix,
+            sender,
+            alive: Arc::downgrade(&alive),
+        });
+        Subscription {
+            inner: SubscriptionInner::Channel {
+                receiver,
+                _alive: alive,
+            },
+        }
+    }
+
+    /// Returns `true` if a subscriber would see a mutation of `key`.
+    pub fn is_watched(&self, key: &str) -> bool {
+        self.subscribers
+            .iter()
+            .any(|subscriber| key.starts_with(subscriber.prefix.as_str()))
+    }
+
+    pub fn publish_set(&mut self, key: &str, value: &str) {
+        self.seq += 1;
+        let seq = self.seq;
+        self.publish(key, || Event::Set {
+            seq,
+            key: key.to_owned(),
+            value: value.to_owned(),
+        });
+    }
+
+    pub fn publish_remove(&mut self, key: &str) {
+        self.seq += 1;
+        let seq = self.seq;
+        self.publish(key, || Event::Remove {
+            seq,
+            key: key.to_owned(),
+        });
+    }
+
+    fn publish<F: Fn() -> Event>(&mut self, key: &str, event: F) {
+        // Disconnected and lagging subscribers are dropped, which ends their stream.
+        // A subscriber that went away is dropped whatever its prefix, or one watching
+        // keys that are never written again would stay forever.
+        self.subscribers.retain(|subscriber| {
+            if key.starts_with(subscriber.prefix.as_str()) {
+                subscriber.sender.try_send(event()).is_ok()
+            } else {
+                subscriber.alive.strong_count() > 0
+            }
+        });
+    }
+}
@@ -1,3 +1,4 @@
+use crate::Digest;
 use failure::Fail;
 use std::io;
 use std::string::FromUtf8Error;
@@ -16,6 +17,42 @@ pub enum KvsError {
     SledErr(sled::Error),
     #[fail(display = "utf8 conversion error")]
     Utf8Err,
+    #[fail(display = "{}", _0)]
+    StringErr(String),
+    #[fail(display = "Value of {} is not an integer", _0)]
+    NotAnInteger(String),
+    #[fail(display = "Integer overflow")]
+    IntegerOverflow,
+    #[fail(display = "Directory already contains data")]
+    DirectoryNotEmpty,
+    #[fail(display = "Invalid dump: {}", _0)]
+    InvalidDump(String),
+    #[fail(display = "Invalid value: {}", _0)]
+    InvalidValue(String),
+    #[fail(
+        display = "{}.log is corrupt at offset {}: {} (try `kvs-admin verify`)",
+        gen, offset, cause
+    )]
+    CorruptLog {
+        gen: u64,
+        offset: u64,
+        cause: String,
+    },
+    #[fail(display = "{}.sst is corrupt: {}", gen, cause)]
+    CorruptTable { gen: u64, cause: String },
+    #[fail(display = "Store is read-only after a failed write: {}", _0)]
+    ReadOnly(String),
+    #[fail(display = "Unsupported store format: {}", _0)]
+    UnsupportedFormat(String),
+    #[fail(
+        display = "Migration check failed: copied {} but target has {}",
+        source, target
+    )]
+    MigrationMismatch { source: Digest, target: Digest },
+    #[fail(display = "{} is in use by process {}", dir, owner)]
+    DirectoryLocked { dir: String, owner: String },
+    #[fail(display = "{} is not supported by this engine", _0)]
+    Unsupported(String),
 }
 
 pub type Result<T> = std::result::Result<T, KvsError>;
@@ -1,13 +1,30 @@
 mod client;
 mod common;
+mod dump;
 mod engines;
 mod error;
+mod lock;
+mod migrate;
 mod server;
 mod thread_pool;
 
-pub use client::KvsClient;
-pub use common::{GetResponse, Request, SetOrRemoveResponse};
-pub use engines::{KvStore, KvsEngine, SledStore};
+pub use client::{KvsClient, ValueStream, Watch};
+pub use common::{
+    GetResponse, IncrResponse, PoolStatsResponse, Request, SetOrRemoveResponse, StatsResponse,
+    ValueChunk, WatchResponse,
+};
+pub use dump::{dump, restore, DumpFormat, DumpReader, DumpSource};
+pub use engines::{
+    Capacity, EngineStats, Event, EvictionPolicy, GenerationReport, Health, KvStore,
+    KvStoreFollower, KvStoreOptions, KvsEngine, LsmStore, MemStore, RepairReport, SledStore,
+    StoreFormat, Subscription, VerifyReport,
+};
 pub use error::{KvsError, Result};
-pub use server::KvsServer;
-pub use thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+pub use lock::DirLock;
+pub use migrate::{digest, finish_swap, migrate, swap_dirs, swap_pending, Digest};
+pub use server::{KvsServer, ServerHandle};
+pub use thread_pool::{
+    ElasticOptions, ElasticThreadPool, JoinHandle, NaiveThreadPool, Panic, PoolMetrics, PoolStats,
+    RayonThreadPool, SharedQueueThreadPool, ThreadPool, WaitHistogram, WorkStealingThreadPool,
+    WAIT_BUCKETS,
+};
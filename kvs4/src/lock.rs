@@ -0,0 +1,46 @@
+use crate::{KvsError, Result};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process;
+
+/// Name of the lock file in a data directory.
+const LOCK_FILE: &str = "lock";
+
+/// Keeps other processes from opening a data directory while it is held.
+///
+/// The lock is an exclusive lock on a file in the directory, which the OS releases
+/// when the `DirLock` is dropped or the process dies. It stays with the directory
+/// if that is renamed.
+pub struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Takes the lock of `dir`, or fails with `KvsError::DirectoryLocked` if another
+    /// process holds it.
+    pub fn acquire(dir: &Path) -> Result<DirLock> {
+        let path = dir.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(KvsError::DirectoryLocked {
+                    dir: dir.display().to_string(),
+                    owner: fs::read_to_string(&path)?.trim().to_owned(),
+                })
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        // the id of the holder, for the error above
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", process::id())?;
+        Ok(DirLock { _file: file })
+    }
+}
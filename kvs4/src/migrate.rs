@@ -0,0 +1,129 @@
+use crate::engines::{fnv1a, KvsEngine};
+use crate::{DumpSource, KvsError, Result};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const FNV_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Summary of the live data in an engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Digest {
+    /// Number of live keys
+    pub keys: u64,
+    /// Checksum over all key/value pairs that does not depend on iteration order
+    pub checksum: u64,
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} keys, checksum {:016x}", self.keys, self.checksum)
+    }
+}
+
+/// Computes the `Digest` of every live key/value pair in `source`.
+pub fn digest<S: DumpSource>(source: &S) -> Result<Digest> {
+    let mut digest = Digest {
+        keys: 0,
+        checksum: 0,
+    };
+    source.for_each_pair(|key, value| {
+        digest.add(&key, &value);
+        Ok(())
+    })?;
+    Ok(digest)
+}
+
+/// Copies all live data from `source` into `target` and checks that both ends up
+/// with the same key count and checksum.
+///
+/// The source is only read, so a `KvStoreFollower` can stand in for a `KvStore`.
+pub fn migrate<S: DumpSource, T: KvsEngine>(source: &S, target: &T) -> Result<Digest> {
+    let mut copied = Digest {
+        keys: 0,
+        checksum: 0,
+    };
+    source.for_each_pair(|key, value| {
+        copied.add(&key, &value);
+        target.set(key, value)
+    })?;
+
+    let written = digest(target)?;
+    if written != copied {
+        return Err(KvsError::MigrationMismatch {
+            source: copied,
+            target: written,
+        });
+    }
+    Ok(written)
+}
+
+/// Replaces `dir` with `staging`, moving `dir` to `backup`.
+///
+/// The two renames are preceded by a marker next to `dir`, so that a swap cut
+/// short by a crash is completed by `finish_swap` instead of leaving `dir` missing.
+pub fn swap_dirs(dir: &Path, staging: &Path, backup: &Path) -> Result<()> {
+    let marker = swap_marker(dir);
+    let temp = marker.with_extension("tmp");
+    let mut file = File::create(&temp)?;
+    writeln!(file, "{}", staging.display())?;
+    writeln!(file, "{}", backup.display())?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&temp, &marker)?;
+    finish_swap(dir)?;
+    Ok(())
+}
+
+/// Completes a swap of `dir` started by `swap_dirs`.
+/// Returns whether one was pending.
+pub fn finish_swap(dir: &Path) -> Result<bool> {
+    let marker = swap_marker(dir);
+    if !marker.exists() {
+        return Ok(false);
+    }
+    let content = fs::read_to_string(&marker)?;
+    let mut lines = content.lines().map(PathBuf::from);
+    let (staging, backup) = match (lines.next(), lines.next()) {
+        (Some(staging), Some(backup)) => (staging, backup),
+        _ => {
+            return Err(KvsError::StringErr(format!(
+                "{} is invalid",
+                marker.display()
+            )))
+        }
+    };
+    // Once `staging` is gone, both renames are done.
+    if staging.exists() {
+        if dir.exists() {
+            fs::rename(dir, &backup)?;
+        }
+        fs::rename(&staging, dir)?;
+    }
+    fs::remove_file(&marker)?;
+    Ok(true)
+}
+
+/// Returns whether `dir` is in the middle of a swap that `finish_swap` completes.
+pub fn swap_pending(dir: &Path) -> bool {
+    swap_marker(dir).exists()
+}
+
+/// Returns `<dir>.swap` next to `dir`.
+fn swap_marker(dir: &Path) -> PathBuf {
+    let mut name = OsString::from(dir.file_name().unwrap_or_default());
+    name.push(".swap");
+    dir.with_file_name(name)
+}
+
+impl Digest {
+    fn add(&mut self, key: &str, value: &str) {
+        // the length of the key keeps ("ab", "c") apart from ("a", "bc")
+        let hash = fnv1a(&(key.len() as u64).to_le_bytes(), FNV_BASIS);
+        let hash = fnv1a(value.as_bytes(), fnv1a(key.as_bytes(), hash));
+        self.keys += 1;
+        self.checksum = self.checksum.wrapping_add(hash);
+    }
+}
@@ -1,68 +1,571 @@
-use super::common::{GetResponse, Request, SetOrRemoveResponse};
-use super::engines::KvsEngine;
+use super::common::{
+    BusyResponse, GetResponse, IncrResponse, PoolStatsResponse, Request, SetOrRemoveResponse,
+    StatsResponse, Utf8Chunks, ValueChunk, WatchResponse,
+};
+use super::engines::{KvsEngine, Subscription};
 use super::error::Result;
-use log::{debug, error};
+use super::thread_pool::{PoolMetrics, ThreadPool};
+use log::{debug, error, warn};
 use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-pub struct KvsServer<E: KvsEngine> {
+/// How long the acceptor reads what a rejected client sent, before it closes the
+/// connection.
+const BUSY_LINGER: Duration = Duration::from_millis(100);
+
+/// How often a watch with no events checks whether the client went away or the
+/// server is stopping.
+const WATCH_POLL: Duration = Duration::from_millis(100);
+
+/// How many watches a server streams at once, unless set with `max_watches`.
+const DEFAULT_MAX_WATCHES: usize = 1024;
+
+/// Serves clients over TCP, each connection on a job of the thread pool.
+///
+/// A client that connects while the queue of the pool is full is told the server is
+/// busy, instead of waiting for a worker. Watches stream on threads of their own, so
+/// they are limited separately.
+pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
+    pool: P,
+    max_watches: usize,
 }
 
-impl<E: KvsEngine> KvsServer<E> {
-    pub fn new(engine: E) -> Self {
-        KvsServer { engine }
+impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+    pub fn new(engine: E, pool: P) -> Self {
+        KvsServer {
+            engine,
+            pool,
+            max_watches: DEFAULT_MAX_WATCHES,
+        }
+    }
+
+    /// Sets how many watches are streamed at once. Clients asking for more are told
+    /// the server is busy.
+    pub fn max_watches(mut self, max: usize) -> Self {
+        self.max_watches = max;
+        self
+    }
+
+    /// Serves clients on `addr` until the process ends.
+    pub fn run(self, addr: SocketAddr) -> Result<()> {
+        let handle = self.start(addr)?;
+        handle.acceptor.join().unwrap()?;
+        Ok(())
     }
 
-    pub fn run(mut self, addr: SocketAddr) -> Result<()> {
+    /// Starts accepting clients on `addr` in the background.
+    /// The returned handle stops the server.
+    pub fn start(self, addr: SocketAddr) -> Result<ServerHandle<E, P>> {
         let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let connections = Arc::new(Connections::new(self.max_watches));
+        let engine = self.engine.clone();
+        let acceptor = {
+            let connections = Arc::clone(&connections);
+            thread::Builder::new()
+                .name("kvs-acceptor".to_owned())
+                .spawn(move || self.accept(listener, &connections))?
+        };
+        Ok(ServerHandle {
+            engine,
+            local_addr,
+            connections,
+            acceptor,
+        })
+    }
+
+    /// Hands every accepted connection to the pool until the server is stopped.
+    /// Returns the pool for the workers to be waited for.
+    fn accept(self, listener: TcpListener, connections: &Arc<Connections>) -> Result<P> {
+        let metrics = self.pool.metrics();
         for stream in listener.incoming() {
             match stream {
                 Ok(stream) => {
-                    if let Err(e) = self.serve(stream) {
-                        error!("Error on serving client: {}", e);
+                    let id = match connections.open(&stream)? {
+                        Some(id) => id,
+                        None => break,
+                    };
+                    let engine = self.engine.clone();
+                    let job = {
+                        let connections = Arc::clone(connections);
+                        let metrics = metrics.clone();
+                        move || match serve(engine, &stream, &connections, id, &metrics) {
+                            Ok(Some((subscription, slot))) => {
+                                watch(stream, subscription, slot, &connections, id)
+                            }
+                            Ok(None) => connections.close(id),
+                            Err(e) => {
+                                error!("Error on serving client: {}", e);
+                                connections.close(id);
+                            }
+                        }
+                    };
+                    if self.pool.try_spawn(job).is_err() {
+                        connections.reject(id);
                     }
                 }
                 Err(e) => error!("Connection failed: {}", e),
             }
         }
-        Ok(())
+        Ok(self.pool)
+    }
+}
+
+/// Stops a server started by `KvsServer::start`.
+pub struct ServerHandle<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    local_addr: SocketAddr,
+    connections: Arc<Connections>,
+    acceptor: JoinHandle<Result<P>>,
+}
+
+impl<E: KvsEngine, P: ThreadPool> ServerHandle<E, P> {
+    /// The address the server listens on, with the actual port if it was given as 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
     }
 
-    fn serve(&mut self, tcp: TcpStream) -> Result<()> {
-        let peer_addr = tcp.peer_addr()?;
-        let reader = BufReader::new(&tcp);
-        let mut writer = BufWriter::new(&tcp);
-        let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+    /// Stops accepting clients and waits up to `deadline` for the requests being
+    /// served to be answered. Connections are closed once their request is answered,
+    /// watches within `WATCH_POLL`, and any still busy at the deadline are cut off.
+    /// Then the watch threads and the workers of the pool are joined and the engine
+    /// is synced.
+    pub fn shutdown(self, deadline: Duration) -> Result<()> {
+        let started = Instant::now();
+        self.connections.stop();
+        // `accept` only looks at the flag once a client connects
+        let mut wake_addr = self.local_addr;
+        if wake_addr.ip().is_unspecified() {
+            wake_addr.set_ip(match wake_addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let _ = TcpStream::connect(wake_addr);
+        let pool = self.acceptor.join().unwrap()?;
 
-        macro_rules! send_resp {
-            ($resp:expr) => {{
-                let resp = $resp;
-                serde_json::to_writer(&mut writer, &resp)?;
-                writer.flush()?;
-                debug!("Response sent to {}: {:?}", peer_addr, resp);
-            };};
-        }
-
-        for req in req_reader {
-            let req = req?;
-            debug!("Receive request from {}: {:?}", peer_addr, req);
-            match req {
-                Request::Get { key } => send_resp!(match self.engine.get(key) {
-                    Ok(value) => GetResponse::Ok(value),
-                    Err(e) => GetResponse::Err(format!("{}", e)),
-                }),
-                Request::Set { key, value } => send_resp!(match self.engine.set(key, value) {
+        let cut_off = self
+            .connections
+            .wait_closed(deadline.saturating_sub(started.elapsed()));
+        if cut_off > 0 {
+            warn!(
+                "Cut off {} connections still busy after {:?}",
+                cut_off, deadline
+            );
+        }
+        for watcher in self.connections.take_watchers() {
+            let _ = watcher.join();
+        }
+        pool.join();
+        self.engine.sync()
+    }
+}
+
+/// The open connections of a server, so that it can close them when it stops.
+struct Connections {
+    state: Mutex<ConnectionState>,
+    closed: Condvar,
+    max_watches: usize,
+}
+
+#[derive(Default)]
+struct ConnectionState {
+    stopping: bool,
+    next_id: u64,
+    /// Each connection, and whether it is answering a request or streaming events
+    open: HashMap<u64, (TcpStream, bool)>,
+    /// The threads streaming events
+    watchers: Vec<JoinHandle<()>>,
+    /// The watches holding a `WatchSlot`
+    watches: usize,
+}
+
+/// The right to stream a watch, given back when it is dropped.
+struct WatchSlot(Arc<Connections>);
+
+impl Drop for WatchSlot {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().watches -= 1;
+    }
+}
+
+impl Connections {
+    fn new(max_watches: usize) -> Self {
+        Connections {
+            state: Mutex::default(),
+            closed: Condvar::new(),
+            max_watches,
+        }
+    }
+
+    /// Registers a new connection, unless the server is stopping.
+    fn open(&self, tcp: &TcpStream) -> Result<Option<u64>> {
+        let mut state = self.state.lock().unwrap();
+        if state.stopping {
+            return Ok(None);
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.open.insert(id, (tcp.try_clone()?, false));
+        Ok(Some(id))
+    }
+
+    /// Marks the connection as answering a request.
+    fn busy(&self, id: u64) {
+        if let Some((_, busy)) = self.state.lock().unwrap().open.get_mut(&id) {
+            *busy = true;
+        }
+    }
+
+    /// Marks the connection as waiting for its next request.
+    /// Returns `false` if it should be closed instead because the server is stopping.
+    fn idle(&self, id: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if let Some((_, busy)) = state.open.get_mut(&id) {
+            *busy = false;
+        }
+        !state.stopping
+    }
+
+    fn stopping(&self) -> bool {
+        self.state.lock().unwrap().stopping
+    }
+
+    /// Takes a slot for a watch, unless `max_watches` are streamed already.
+    fn start_watch(self: &Arc<Self>) -> Option<WatchSlot> {
+        let mut state = self.state.lock().unwrap();
+        if state.watches >= self.max_watches {
+            return None;
+        }
+        state.watches += 1;
+        Some(WatchSlot(Arc::clone(self)))
+    }
+
+    /// Keeps the thread of a watch, to join it when the server stops.
+    fn watcher(&self, thread: JoinHandle<()>) {
+        let mut state = self.state.lock().unwrap();
+        state.watchers.retain(|watcher| !watcher.is_finished());
+        state.watchers.push(thread);
+    }
+
+    fn take_watchers(&self) -> Vec<JoinHandle<()>> {
+        std::mem::take(&mut self.state.lock().unwrap().watchers)
+    }
+
+    fn close(&self, id: u64) {
+        self.state.lock().unwrap().open.remove(&id);
+        self.closed.notify_all();
+    }
+
+    /// Tells the client that the server is too busy, and closes the connection.
+    fn reject(&self, id: u64) {
+        let tcp = match self.state.lock().unwrap().open.remove(&id) {
+            Some((tcp, _)) => tcp,
+            None => return,
+        };
+        self.closed.notify_all();
+        debug!("Rejecting {:?}: the queue is full", tcp.peer_addr());
+        let busy = BusyResponse::Err("Server busy".to_owned());
+        if serde_json::to_writer(&tcp, &busy).is_err() || tcp.shutdown(Shutdown::Write).is_err() {
+            return;
+        }
+        // Closing with the request of the client unread would reset the connection,
+        // and the client might lose the response. It usually arrives right away.
+        let deadline = Instant::now() + BUSY_LINGER;
+        let mut buf = [0; 1024];
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            if left.is_zero() || tcp.set_read_timeout(Some(left)).is_err() {
+                break;
+            }
+            match (&tcp).read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+    }
+
+    /// Refuses new connections and ends the reads of those waiting for a request.
+    fn stop(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.stopping = true;
+        for (tcp, busy) in state.open.values() {
+            // A busy connection still has to send its response. It sees the flag once
+            // it is done.
+            if !busy {
+                let _ = tcp.shutdown(Shutdown::Read);
+            }
+        }
+    }
+
+    /// Waits up to `timeout` for all connections to close, then cuts off the rest.
+    /// Returns how many were cut off.
+    fn wait_closed(&self, timeout: Duration) -> usize {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self
+            .closed
+            .wait_timeout_while(state, timeout, |state| !state.open.is_empty())
+            .unwrap();
+        for (tcp, _) in state.open.values() {
+            let _ = tcp.shutdown(Shutdown::Both);
+        }
+        state.open.len()
+    }
+}
+
+/// Answers the requests of a client until it disconnects or the server stops.
+/// Returns the subscription and its slot if the client asked to watch, which takes
+/// over the connection.
+fn serve<E: KvsEngine>(
+    engine: E,
+    tcp: &TcpStream,
+    connections: &Arc<Connections>,
+    id: u64,
+    metrics: &PoolMetrics,
+) -> Result<Option<(Subscription, WatchSlot)>> {
+    let peer_addr = tcp.peer_addr()?;
+    let reader = BufReader::new(tcp);
+    let mut writer = BufWriter::new(tcp);
+    let mut req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+
+    macro_rules! send_resp {
+        ($resp:expr) => {{
+            let resp = $resp;
+            serde_json::to_writer(&mut writer, &resp)?;
+            writer.flush()?;
+            debug!("Response sent to {}: {:?}", peer_addr, resp);
+        }};
+    }
+
+    while connections.idle(id) {
+        let req = match req_reader.next() {
+            Some(req) => req?,
+            None => break,
+        };
+        connections.busy(id);
+        debug!("Receive request from {}: {:?}", peer_addr, req);
+        match req {
+            Request::Get { key } => send_resp!(match engine.get(key) {
+                Ok(value) => GetResponse::Ok(value),
+                Err(e) => GetResponse::Err(format!("{}", e)),
+            }),
+            Request::Set { key, value, ttl } => {
+                let res = match ttl {
+                    Some(ttl) => engine.set_with_ttl(key, value, Duration::from_millis(ttl)),
+                    None => engine.set(key, value),
+                };
+                send_resp!(match res {
                     Ok(()) => SetOrRemoveResponse::Ok(()),
                     Err(e) => SetOrRemoveResponse::Err(format!("{}", e)),
-                }),
-                Request::Remove { key } => send_resp!(match self.engine.remove(key) {
+                })
+            }
+            Request::Remove { key } => send_resp!(match engine.remove(key) {
+                Ok(()) => SetOrRemoveResponse::Ok(()),
+                Err(e) => SetOrRemoveResponse::Err(format!("{}", e)),
+            }),
+            Request::Incr { key, delta } => send_resp!(match engine.incr_by(key, delta) {
+                Ok(value) => IncrResponse::Ok(value),
+                Err(e) => IncrResponse::Err(format!("{}", e)),
+            }),
+            Request::Stats => send_resp!(match engine.stats() {
+                Ok(stats) => StatsResponse::Ok(stats),
+                Err(e) => StatsResponse::Err(format!("{}", e)),
+            }),
+            Request::PoolStats => send_resp!(PoolStatsResponse::Ok(metrics.stats())),
+            Request::SetStream { key, len } => {
+                let mut chunks = ChunkReader::new(&mut req_reader, len);
+                let res = engine.set_from_reader(key, len, &mut chunks);
+                // what the engine left of the value must not be taken for requests
+                let drained = io::copy(&mut chunks, &mut io::sink());
+                send_resp!(match res {
                     Ok(()) => SetOrRemoveResponse::Ok(()),
                     Err(e) => SetOrRemoveResponse::Err(format!("{}", e)),
-                }),
+                });
+                // after a broken value it is unknown where the next request starts
+                drained?;
             }
+            Request::Chunk(_) => send_resp!(SetOrRemoveResponse::Err(
+                "Chunk outside of a streamed value".to_owned()
+            )),
+            Request::GetStream { key } => match engine.get_reader(key) {
+                Ok(Some(value)) => {
+                    let mut end = ValueChunk::End;
+                    for chunk in Utf8Chunks::new(value) {
+                        match chunk {
+                            Ok(chunk) => send_resp!(ValueChunk::Chunk(chunk)),
+                            Err(e) => {
+                                end = ValueChunk::Err(format!("{}", e));
+                                break;
+                            }
+                        }
+                    }
+                    send_resp!(end)
+                }
+                Ok(None) => send_resp!(ValueChunk::NotFound),
+                Err(e) => send_resp!(ValueChunk::Err(format!("{}", e))),
+            },
+            Request::Watch { prefix } => match connections.start_watch() {
+                Some(slot) => match engine.subscribe(prefix) {
+                    Ok(subscription) => {
+                        send_resp!(WatchResponse::Subscribed);
+                        return Ok(Some((subscription, slot)));
+                    }
+                    Err(e) => send_resp!(WatchResponse::Err(format!("{}", e))),
+                },
+                None => send_resp!(WatchResponse::Err("Server busy".to_owned())),
+            },
+        }
+    }
+    Ok(None)
+}
+
+/// Streams the events of `subscription` on a thread of its own, so that the
+/// connection doesn't hold a pool worker. The connection counts as busy until the
+/// stream ends.
+fn watch(
+    tcp: TcpStream,
+    subscription: Subscription,
+    slot: WatchSlot,
+    connections: &Arc<Connections>,
+    id: u64,
+) {
+    let thread = {
+        let connections = Arc::clone(connections);
+        thread::Builder::new()
+            .name(format!("kvs-watch-{}", id))
+            .spawn(move || {
+                if let Err(e) = stream_events(&tcp, subscription, &connections) {
+                    debug!("Watch of {:?} ended: {}", tcp.peer_addr(), e);
+                }
+                drop(slot);
+                connections.close(id);
+            })
+    };
+    match thread {
+        Ok(thread) => connections.watcher(thread),
+        Err(e) => {
+            error!("Cannot start a watch: {}", e);
+            connections.close(id);
         }
+    }
+}
+
+/// Reads the value of a `Request::SetStream` from the `Request::Chunk`s after it.
+struct ChunkReader<'a, I> {
+    requests: &'a mut I,
+    chunk: Vec<u8>,
+    pos: usize,
+    // bytes of the value not received yet
+    remaining: u64,
+    // set once the chunks broke the protocol, every read after fails
+    failed: bool,
+}
+
+impl<'a, I> ChunkReader<'a, I> {
+    fn new(requests: &'a mut I, len: u64) -> Self {
+        ChunkReader {
+            requests,
+            chunk: Vec::new(),
+            pos: 0,
+            remaining: len,
+            failed: false,
+        }
+    }
+}
+
+impl<I: Iterator<Item = serde_json::Result<Request>>> ChunkReader<'_, I> {
+    /// Receives the next chunk of the value.
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let chunk = match self.requests.next() {
+            Some(Ok(Request::Chunk(chunk))) => chunk.into_bytes(),
+            Some(Ok(req)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Expected a chunk, got {:?}", req),
+                ))
+            }
+            Some(Err(e)) => return Err(e.into()),
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        };
+        if chunk.len() as u64 > self.remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Chunk of {} bytes exceeds the {} bytes left of the value",
+                    chunk.len(),
+                    self.remaining
+                ),
+            ));
+        }
+        self.remaining -= chunk.len() as u64;
+        self.chunk = chunk;
+        self.pos = 0;
         Ok(())
     }
 }
+
+impl<I: Iterator<Item = serde_json::Result<Request>>> Read for ChunkReader<'_, I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.failed {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Streamed value is broken",
+            ));
+        }
+        while self.pos == self.chunk.len() {
+            if self.remaining == 0 {
+                return Ok(0);
+            }
+            if let Err(e) = self.next_chunk() {
+                self.failed = true;
+                return Err(e);
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Sends every event of the subscription until it ends, the client goes away or
+/// the server stops.
+fn stream_events(
+    tcp: &TcpStream,
+    mut subscription: Subscription,
+    connections: &Connections,
+) -> Result<()> {
+    let mut writer = BufWriter::new(tcp);
+    // only to notice a closed connection, the client sends nothing
+    tcp.set_read_timeout(Some(Duration::from_millis(1)))?;
+    while !connections.stopping() {
+        match subscription.next_timeout(WATCH_POLL) {
+            Ok(event) => {
+                serde_json::to_writer(&mut writer, &WatchResponse::Event(event))?;
+                writer.flush()?;
+            }
+            Err(RecvTimeoutError::Timeout) if client_gone(tcp) => break,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether the client closed the connection.
+fn client_gone(tcp: &TcpStream) -> bool {
+    match tcp.peek(&mut [0]) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => !matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ),
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::sync::WaitGroup;
+use log::{debug, error, warn};
+
+use super::queue::{Queue, Slot};
+use super::{panic_message, PoolMetrics, ThreadPool};
+use crate::{KvsError, Result};
+
+/// Options of an `ElasticThreadPool`.
+#[derive(Debug, Clone)]
+pub struct ElasticOptions {
+    /// Workers kept even when there is nothing to do
+    pub min_workers: u32,
+    /// Workers the pool grows to at most
+    pub max_workers: u32,
+    /// How long a job may wait in the queue before a worker is added for it
+    pub wait_threshold: Duration,
+    /// How long a worker beyond `min_workers` waits for a job before it exits
+    pub keep_alive: Duration,
+    /// Jobs held back beyond this many waiting to start, or none if `None`
+    pub queue_capacity: Option<usize>,
+}
+
+impl Default for ElasticOptions {
+    fn default() -> Self {
+        ElasticOptions {
+            min_workers: 1,
+            max_workers: 16,
+            wait_threshold: Duration::from_millis(10),
+            keep_alive: Duration::from_secs(60),
+            queue_capacity: None,
+        }
+    }
+}
+
+/// A pool whose workers follow the load: it starts with `min_workers`, adds one
+/// whenever a job has waited longer than `wait_threshold` with no worker free, up to
+/// `max_workers`, and lets workers beyond `min_workers` exit once they have been
+/// idle for `keep_alive`.
+///
+/// `ThreadPool::new(threads)` grows from one worker to `threads`.
+pub struct ElasticThreadPool {
+    shared: Arc<Shared>,
+    /// Held by the scaler and every worker until they exit
+    workers: WaitGroup,
+    queue: Arc<Queue>,
+}
+
+struct Shared {
+    options: ElasticOptions,
+    state: Mutex<State>,
+    /// Signals workers that a job was queued or the pool shut down
+    job_queued: Condvar,
+    /// Signals the scaler that the jobs or the workers changed
+    changed: Condvar,
+    metrics: PoolMetrics,
+}
+
+struct State {
+    jobs: VecDeque<Job>,
+    /// Workers running, including the ones starting up
+    workers: u32,
+    /// Workers waiting for a job, including the ones starting up
+    idle: u32,
+    next_worker: u32,
+    stopped: bool,
+}
+
+struct Job {
+    queued_at: Instant,
+    run: Box<dyn FnOnce() + Send + 'static>,
+}
+
+impl ElasticThreadPool {
+    /// Creates a pool with the given options.
+    pub fn with_options(options: ElasticOptions) -> Result<Self> {
+        if options.max_workers == 0 || options.min_workers > options.max_workers {
+            return Err(KvsError::StringErr(format!(
+                "Invalid elastic pool size: {} to {} workers",
+                options.min_workers, options.max_workers
+            )));
+        }
+        let queue = Queue::new(options.queue_capacity);
+        let shared = Arc::new(Shared {
+            options,
+            state: Mutex::new(State {
+                jobs: VecDeque::new(),
+                workers: 0,
+                idle: 0,
+                next_worker: 0,
+                stopped: false,
+            }),
+            job_queued: Condvar::new(),
+            changed: Condvar::new(),
+            metrics: PoolMetrics::default(),
+        });
+        // dropped on error, which stops the threads started so far
+        let pool = ElasticThreadPool {
+            shared,
+            workers: WaitGroup::new(),
+            queue,
+        };
+        {
+            let mut state = pool.shared.state.lock().unwrap();
+            for _ in 0..pool.shared.options.min_workers {
+                add_worker(&pool.shared, &mut state, &pool.workers)?;
+            }
+        }
+        {
+            let shared = Arc::clone(&pool.shared);
+            let workers = pool.workers.clone();
+            thread::Builder::new()
+                .name("elastic-scaler".to_owned())
+                .spawn(move || run_scaler(&shared, workers))?;
+        }
+        Ok(pool)
+    }
+
+    fn send<F>(&self, job: F, slot: Slot)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.stopped {
+            warn!("Dropping a job spawned after the thread pool was shut down");
+            return;
+        }
+        state.jobs.push_back(Job {
+            queued_at: Instant::now(),
+            run: Box::new(self.shared.metrics.queue(move || {
+                drop(slot);
+                job()
+            })),
+        });
+        self.shared.job_queued.notify_one();
+        self.shared.changed.notify_one();
+    }
+}
+
+impl ThreadPool for ElasticThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        ElasticThreadPool::with_options(ElasticOptions {
+            max_workers: threads,
+            ..ElasticOptions::default()
+        })
+    }
+
+    fn with_queue_capacity(threads: u32, capacity: usize) -> Result<Self> {
+        ElasticThreadPool::with_options(ElasticOptions {
+            max_workers: threads,
+            queue_capacity: Some(capacity),
+            ..ElasticOptions::default()
+        })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.send(job, self.queue.reserve())
+    }
+
+    fn try_spawn<F>(&self, job: F) -> std::result::Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.queue.try_reserve() {
+            Some(slot) => {
+                self.send(job, slot);
+                Ok(())
+            }
+            None => Err(job),
+        }
+    }
+
+    fn metrics(&self) -> PoolMetrics {
+        self.shared.metrics.clone()
+    }
+
+    fn shutdown(&self) {
+        self.shared.state.lock().unwrap().stopped = true;
+        self.shared.job_queued.notify_all();
+        self.shared.changed.notify_all();
+    }
+
+    fn join(self) {
+        self.shutdown();
+        let workers = self.workers.clone();
+        drop(self);
+        workers.wait();
+    }
+}
+
+impl Drop for ElasticThreadPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Starts a worker, counted as idle until it takes a job.
+fn add_worker(shared: &Arc<Shared>, state: &mut State, running: &WaitGroup) -> Result<()> {
+    let worker = state.next_worker;
+    {
+        let shared = Arc::clone(shared);
+        let running = running.clone();
+        thread::Builder::new()
+            .name(format!("elastic-worker-{}", worker))
+            .spawn(move || run_worker(worker, shared, running))?;
+    }
+    state.next_worker += 1;
+    state.workers += 1;
+    state.idle += 1;
+    Ok(())
+}
+
+/// Adds a worker whenever the oldest job has waited past the threshold and no
+/// worker is free to take it, until the pool is shut down and its queue is empty.
+fn run_scaler(shared: &Arc<Shared>, running: WaitGroup) {
+    let threshold = shared.options.wait_threshold;
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        let waited = match state.jobs.front() {
+            Some(job) => job.queued_at.elapsed(),
+            None if state.stopped => return,
+            None => {
+                state = shared.changed.wait(state).unwrap();
+                continue;
+            }
+        };
+        if state.idle > 0 || state.workers >= shared.options.max_workers {
+            // a worker takes the job, or one frees up
+            state = shared.changed.wait(state).unwrap();
+            continue;
+        }
+        if waited < threshold {
+            state = shared
+                .changed
+                .wait_timeout(state, threshold - waited)
+                .unwrap()
+                .0;
+            continue;
+        }
+        debug!(
+            "A job has waited {:?}, adding elastic-worker-{}",
+            waited, state.next_worker
+        );
+        if let Err(e) = add_worker(shared, &mut state, &running) {
+            error!("Failed to add an elastic worker: {}", e);
+            state = shared.changed.wait_timeout(state, threshold).unwrap().0;
+        }
+    }
+}
+
+/// Runs jobs until the pool is shut down and the queue is empty, until it is idle
+/// for the keep-alive with more than the minimum of workers, or until a job panics
+/// and the worker hands its place to a new thread.
+fn run_worker(worker: u32, shared: Arc<Shared>, running: WaitGroup) {
+    let _worker = shared.metrics.worker();
+    loop {
+        let job = match next_job(&shared, shared.state.lock().unwrap()) {
+            Some(job) => job,
+            None => return,
+        };
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job.run)) {
+            error!(
+                "Job panicked on elastic-worker-{}: {}",
+                worker,
+                panic_message(&*payload)
+            );
+            // The job may have left thread locals in a bad state, so the thread
+            // is not reused.
+            let mut state = shared.state.lock().unwrap();
+            state.workers -= 1;
+            if let Err(e) = add_worker(&shared, &mut state, &running) {
+                error!("Failed to replace elastic-worker-{}: {}", worker, e);
+            }
+            shared.changed.notify_one();
+            return;
+        }
+        shared.state.lock().unwrap().idle += 1;
+        shared.changed.notify_one();
+    }
+}
+
+/// Takes the next job as an idle worker, or returns `None` once the worker is to
+/// exit, with the worker no longer counted.
+fn next_job(shared: &Shared, mut state: MutexGuard<'_, State>) -> Option<Job> {
+    let options = &shared.options;
+    loop {
+        if let Some(job) = state.jobs.pop_front() {
+            state.idle -= 1;
+            shared.changed.notify_one();
+            return Some(job);
+        }
+        if state.stopped {
+            break;
+        }
+        let (guard, timeout) = shared
+            .job_queued
+            .wait_timeout_while(state, options.keep_alive, |state| {
+                state.jobs.is_empty() && !state.stopped
+            })
+            .unwrap();
+        state = guard;
+        if timeout.timed_out() && state.workers > options.min_workers {
+            break;
+        }
+    }
+    state.idle -= 1;
+    state.workers -= 1;
+    shared.changed.notify_one();
+    None
+}
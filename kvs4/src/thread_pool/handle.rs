@@ -0,0 +1,62 @@
+use std::fmt;
+
+use crossbeam::channel::{self, Receiver, Sender, TryRecvError};
+
+/// The result of a job spawned with `ThreadPool::spawn_with_handle`.
+pub struct JoinHandle<T> {
+    receiver: Receiver<Result<T, Panic>>,
+}
+
+/// A job that panicked, or that was dropped before it ran.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Panic {
+    message: String,
+}
+
+impl<T> JoinHandle<T> {
+    pub(super) fn new() -> (JoinHandle<T>, Sender<Result<T, Panic>>) {
+        let (sender, receiver) = channel::bounded(1);
+        (JoinHandle { receiver }, sender)
+    }
+
+    /// Waits for the job to finish and returns what it returned.
+    pub fn join(self) -> Result<T, Panic> {
+        self.receiver
+            .recv()
+            .unwrap_or_else(|_| Err(Panic::dropped()))
+    }
+
+    /// Returns what the job returned if it has finished, or the handle otherwise.
+    pub fn try_join(self) -> Result<Result<T, Panic>, JoinHandle<T>> {
+        match self.receiver.try_recv() {
+            Ok(res) => Ok(res),
+            Err(TryRecvError::Empty) => Err(self),
+            Err(TryRecvError::Disconnected) => Ok(Err(Panic::dropped())),
+        }
+    }
+}
+
+impl Panic {
+    pub(super) fn new(message: &str) -> Panic {
+        Panic {
+            message: message.to_owned(),
+        }
+    }
+
+    fn dropped() -> Panic {
+        Panic::new("The job was dropped before it ran")
+    }
+
+    /// The message the job panicked with.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for Panic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Job panicked: {}", self.message)
+    }
+}
+
+impl std::error::Error for Panic {}
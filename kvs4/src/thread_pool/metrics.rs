@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the buckets of `WaitHistogram`, after which comes a last bucket
+/// for longer waits.
+pub const WAIT_BUCKETS: [Duration; 5] = [
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+/// A snapshot of the counters of a pool.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolStats {
+    /// Workers running a job
+    pub active_workers: usize,
+    /// Workers waiting for a job
+    pub idle_workers: usize,
+    /// Jobs spawned that have not started yet
+    pub queue_depth: usize,
+    /// Jobs that returned
+    pub jobs_completed: u64,
+    /// Jobs that panicked
+    pub jobs_panicked: u64,
+    /// How long the jobs that started waited in the queue
+    pub queue_wait: WaitHistogram,
+}
+
+/// Counts of waits by duration.
+///
+/// `counts[i]` is the number of waits shorter than `WAIT_BUCKETS[i]` and not
+/// counted in a bucket before it. The last count is of waits of `WAIT_BUCKETS`'s
+/// last bound or longer.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WaitHistogram {
+    pub counts: [u64; WAIT_BUCKETS.len() + 1],
+}
+
+impl WaitHistogram {
+    /// The upper bound of each bucket, `None` for the last one, with its count.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        WAIT_BUCKETS
+            .iter()
+            .map(|&bound| Some(bound))
+            .chain(Some(None))
+            .zip(self.counts.iter().copied())
+    }
+
+    /// The number of waits counted.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+/// The counters of a pool, kept up to date by its workers.
+///
+/// Cloning gives another handle to the same counters, which can be read from any
+/// thread, e.g. by the jobs of the pool.
+#[derive(Clone, Default)]
+pub struct PoolMetrics(Arc<Counters>);
+
+#[derive(Default)]
+struct Counters {
+    workers: AtomicUsize,
+    active: AtomicUsize,
+    queued: AtomicUsize,
+    completed: AtomicU64,
+    panicked: AtomicU64,
+    waits: [AtomicU64; WAIT_BUCKETS.len() + 1],
+}
+
+/// Counts a worker until dropped.
+pub(super) struct Worker(PoolMetrics);
+
+/// Counts a job as active until dropped, then as completed or panicked.
+struct Running(PoolMetrics);
+
+impl PoolMetrics {
+    /// Reads the counters.
+    ///
+    /// They are read one by one while the pool runs, so they may be off by the jobs
+    /// starting or ending at that time.
+    pub fn stats(&self) -> PoolStats {
+        let counters = &self.0;
+        let active = counters.active.load(Ordering::Relaxed);
+        let mut queue_wait = WaitHistogram::default();
+        for (count, wait) in queue_wait.counts.iter_mut().zip(&counters.waits) {
+            *count = wait.load(Ordering::Relaxed);
+        }
+        PoolStats {
+            active_workers: active,
+            idle_workers: counters
+                .workers
+                .load(Ordering::Relaxed)
+                .saturating_sub(active),
+            queue_depth: counters.queued.load(Ordering::Relaxed),
+            jobs_completed: counters.completed.load(Ordering::Relaxed),
+            jobs_panicked: counters.panicked.load(Ordering::Relaxed),
+            queue_wait,
+        }
+    }
+
+    /// Counts the calling thread as a worker until the returned guard is dropped.
+    pub(super) fn worker(&self) -> Worker {
+        self.worker_started();
+        Worker(self.clone())
+    }
+
+    pub(super) fn worker_started(&self) {
+        self.0.workers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn worker_exited(&self) {
+        self.0.workers.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Takes back a job counted by `queue` that is dropped without running.
+    pub(super) fn unqueue(&self) {
+        self.0.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Counts `job` as queued, and wraps it to count it as it runs.
+    /// The returned job must run, or be taken back with `unqueue`.
+    pub(super) fn queue<F>(&self, job: F) -> impl FnOnce() + Send + 'static
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.0.queued.fetch_add(1, Ordering::Relaxed);
+        let queued_at = Instant::now();
+        let metrics = self.clone();
+        move || {
+            let counters = &metrics.0;
+            counters.queued.fetch_sub(1, Ordering::Relaxed);
+            let waited = queued_at.elapsed();
+            let bucket = WAIT_BUCKETS
+                .iter()
+                .position(|&bound| waited < bound)
+                .unwrap_or(WAIT_BUCKETS.len());
+            counters.waits[bucket].fetch_add(1, Ordering::Relaxed);
+            counters.active.fetch_add(1, Ordering::Relaxed);
+            let _running = Running(metrics);
+            job()
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.0.worker_exited();
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let counters = &(self.0).0;
+        counters.active.fetch_sub(1, Ordering::Relaxed);
+        if thread::panicking() {
+            counters.panicked.fetch_add(1, Ordering::Relaxed);
+        } else {
+            counters.completed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
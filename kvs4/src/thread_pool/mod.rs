@@ -1,19 +1,98 @@
 use crate::Result;
 
-pub trait ThreadPool {
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+
+/// A pool of threads running jobs.
+///
+/// Dropping a pool shuts it down: the jobs spawned before still run, but the drop
+/// does not wait for them. Use `join` to wait.
+pub trait ThreadPool: Send + 'static {
+    /// Creates a pool with a queue of any length.
     fn new(threads: u32) -> Result<Self>
     where
         Self: Sized;
 
+    /// Creates a pool that holds back jobs beyond `capacity` waiting to start.
+    fn with_queue_capacity(threads: u32, capacity: usize) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Runs `job` on the pool, waiting for room in the queue if it is full.
+    /// After `shutdown` the job is dropped without running.
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// Runs `job` on the pool, or returns it if the queue is full.
+    fn try_spawn<F>(&self, job: F) -> std::result::Result<(), F>
+    where
+        F: FnOnce() + Send + 'static;
+
+    /// Runs `job` on the pool and returns a handle to what it returns.
+    ///
+    /// A panic of the job is passed to the handle, and then handled by the pool as
+    /// with `spawn`. A job dropped after `shutdown` also ends in a `Panic`.
+    fn spawn_with_handle<F, T>(&self, job: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (handle, sender) = JoinHandle::new();
+        self.spawn(move || match panic::catch_unwind(AssertUnwindSafe(job)) {
+            Ok(value) => {
+                let _ = sender.send(Ok(value));
+            }
+            Err(payload) => {
+                let _ = sender.send(Err(Panic::new(panic_message(&*payload))));
+                panic::resume_unwind(payload);
+            }
+        });
+        handle
+    }
+
+    /// Returns the counters of the pool, which stay readable from other threads.
+    fn metrics(&self) -> PoolMetrics;
+
+    /// Reads the counters of the pool.
+    fn stats(&self) -> PoolStats {
+        self.metrics().stats()
+    }
+
+    /// Stops taking new jobs. The jobs spawned before still run.
+    fn shutdown(&self);
+
+    /// Shuts the pool down and waits until its jobs are done and its threads have
+    /// exited.
+    fn join(self)
+    where
+        Self: Sized;
 }
 
+mod elastic;
+mod handle;
+mod metrics;
 mod naive;
+mod queue;
 mod rayon;
 mod shared_queue;
+mod work_stealing;
 
+pub use self::elastic::{ElasticOptions, ElasticThreadPool};
+pub use self::handle::{JoinHandle, Panic};
+pub use self::metrics::{PoolMetrics, PoolStats, WaitHistogram, WAIT_BUCKETS};
 pub use self::naive::NaiveThreadPool;
 pub use self::rayon::RayonThreadPool;
 pub use self::shared_queue::SharedQueueThreadPool;
+pub use self::work_stealing::WorkStealingThreadPool;
+
+/// Returns the message a job panicked with, if it was given one.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "Box<dyn Any>"
+    }
+}
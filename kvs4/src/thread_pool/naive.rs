@@ -1,19 +1,101 @@
-use std::thread;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
 
-use super::ThreadPool;
+use log::warn;
+
+use super::queue::{Queue, Slot};
+use super::{PoolMetrics, ThreadPool};
 use crate::Result;
 
-pub struct NaiveThreadPool {}
+/// Runs every job on a new thread.
+///
+/// Jobs never wait to start, so its queue capacity limits the jobs running at once.
+pub struct NaiveThreadPool {
+    state: Mutex<NaiveState>,
+    queue: Arc<Queue>,
+    metrics: PoolMetrics,
+}
+
+struct NaiveState {
+    stopped: bool,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl NaiveThreadPool {
+    fn build(capacity: Option<usize>) -> Self {
+        NaiveThreadPool {
+            state: Mutex::new(NaiveState {
+                stopped: false,
+                threads: Vec::new(),
+            }),
+            queue: Queue::new(capacity),
+            metrics: PoolMetrics::default(),
+        }
+    }
+
+    fn start<F>(&self, job: F, slot: Slot)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.state.lock().unwrap();
+        if state.stopped {
+            warn!("Dropping a job spawned after the thread pool was shut down");
+            return;
+        }
+        state.threads.retain(|thread| !thread.is_finished());
+        let job = self.metrics.queue(job);
+        let metrics = self.metrics.clone();
+        state.threads.push(thread::spawn(move || {
+            let _worker = metrics.worker();
+            let _slot = slot;
+            job()
+        }));
+    }
+}
 
 impl ThreadPool for NaiveThreadPool {
-    fn new(threads: u32) -> Result<Self> {
-        Ok(NaiveThreadPool {})
+    fn new(_threads: u32) -> Result<Self> {
+        Ok(NaiveThreadPool::build(None))
+    }
+
+    fn with_queue_capacity(_threads: u32, capacity: usize) -> Result<Self> {
+        Ok(NaiveThreadPool::build(Some(capacity)))
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(job);
+        self.start(job, self.queue.reserve())
+    }
+
+    fn try_spawn<F>(&self, job: F) -> std::result::Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.queue.try_reserve() {
+            Some(slot) => {
+                self.start(job, slot);
+                Ok(())
+            }
+            None => Err(job),
+        }
+    }
+
+    fn metrics(&self) -> PoolMetrics {
+        self.metrics.clone()
+    }
+
+    fn shutdown(&self) {
+        self.state.lock().unwrap().stopped = true;
+    }
+
+    fn join(self) {
+        self.shutdown();
+        let threads = std::mem::take(&mut self.state.lock().unwrap().threads);
+        for thread in threads {
+            // a panic has already been reported by the thread
+            let _ = thread.join();
+        }
     }
 }
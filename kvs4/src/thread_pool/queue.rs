@@ -0,0 +1,54 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Counts the jobs waiting in a pool, and holds back new ones beyond its capacity.
+pub(super) struct Queue {
+    capacity: Option<usize>,
+    len: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// A place in a `Queue`, given up when dropped.
+pub(super) struct Slot(Arc<Queue>);
+
+impl Queue {
+    /// A queue of at most `capacity` jobs, or of any number if `None`.
+    pub fn new(capacity: Option<usize>) -> Arc<Queue> {
+        Arc::new(Queue {
+            capacity,
+            len: Mutex::new(0),
+            freed: Condvar::new(),
+        })
+    }
+
+    /// Takes a place, waiting for one to be free.
+    pub fn reserve(self: &Arc<Self>) -> Slot {
+        let len = self.len.lock().unwrap();
+        let mut len = self
+            .freed
+            .wait_while(len, |len| self.is_full(*len))
+            .unwrap();
+        *len += 1;
+        Slot(Arc::clone(self))
+    }
+
+    /// Takes a place if one is free.
+    pub fn try_reserve(self: &Arc<Self>) -> Option<Slot> {
+        let mut len = self.len.lock().unwrap();
+        if self.is_full(*len) {
+            return None;
+        }
+        *len += 1;
+        Some(Slot(Arc::clone(self)))
+    }
+
+    fn is_full(&self, len: usize) -> bool {
+        self.capacity.is_some_and(|capacity| len >= capacity)
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        *self.0.len.lock().unwrap() -= 1;
+        self.0.freed.notify_one();
+    }
+}
@@ -1,17 +1,173 @@
-use super::ThreadPool;
-use crate::Result;
+use std::sync::{Arc, Condvar, Mutex};
 
-pub struct RayonThreadPool {}
+use log::{error, warn};
+
+use super::queue::{Queue, Slot};
+use super::{panic_message, PoolMetrics, ThreadPool};
+use crate::{KvsError, Result};
+
+/// A thread pool of its own built by rayon, so jobs do not compete with the
+/// global rayon pool the engines use for loading.
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+    jobs: Arc<Counter>,
+    threads: Arc<Counter>,
+    queue: Arc<Queue>,
+    metrics: PoolMetrics,
+}
+
+/// Counts the jobs or threads that have not finished yet.
+#[derive(Default)]
+struct Counter {
+    state: Mutex<CounterState>,
+    zero: Condvar,
+}
+
+#[derive(Default)]
+struct CounterState {
+    count: usize,
+    /// Set on shutdown, after which nothing more is counted
+    stopped: bool,
+}
+
+impl Counter {
+    /// Counts one more, unless the counter is stopped.
+    fn increment(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !state.stopped {
+            state.count += 1;
+        }
+        !state.stopped
+    }
+
+    fn decrement(&self) {
+        self.state.lock().unwrap().count -= 1;
+        self.zero.notify_all();
+    }
+
+    fn stop(&self) {
+        self.state.lock().unwrap().stopped = true;
+    }
+
+    fn wait_zero(&self) {
+        let state = self.state.lock().unwrap();
+        drop(
+            self.zero
+                .wait_while(state, |state| state.count > 0)
+                .unwrap(),
+        );
+    }
+}
+
+/// Counts a job as finished even if it panics.
+struct JobGuard(Arc<Counter>);
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        self.0.decrement();
+    }
+}
+
+impl RayonThreadPool {
+    fn build(threads: u32, capacity: Option<usize>) -> Result<Self> {
+        let exited = Arc::new(Counter::default());
+        let metrics = PoolMetrics::default();
+        let pool = {
+            let exited = Arc::clone(&exited);
+            let started = metrics.clone();
+            let stopped = metrics.clone();
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads as usize)
+                .thread_name(|worker| format!("rayon-worker-{}", worker))
+                .panic_handler(|payload| {
+                    error!(
+                        "Job panicked on {}: {}",
+                        std::thread::current().name().unwrap_or("rayon worker"),
+                        panic_message(&*payload)
+                    )
+                })
+                .start_handler(move |_| started.worker_started())
+                .exit_handler(move |_| {
+                    stopped.worker_exited();
+                    exited.decrement()
+                })
+                .build()
+                .map_err(|e| KvsError::StringErr(format!("Failed to build rayon pool: {}", e)))?
+        };
+        // threads only exit once the pool is dropped
+        for _ in 0..pool.current_num_threads() {
+            exited.increment();
+        }
+        Ok(RayonThreadPool {
+            pool,
+            jobs: Arc::new(Counter::default()),
+            threads: exited,
+            queue: Queue::new(capacity),
+            metrics,
+        })
+    }
+
+    fn send<F>(&self, job: F, slot: Slot)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if !self.jobs.increment() {
+            warn!("Dropping a job spawned after the thread pool was shut down");
+            return;
+        }
+        let guard = JobGuard(Arc::clone(&self.jobs));
+        let job = self.metrics.queue(move || {
+            drop(slot);
+            job()
+        });
+        self.pool.spawn(move || {
+            let _guard = guard;
+            job()
+        })
+    }
+}
 
 impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<Self> {
-        todo!()
+        RayonThreadPool::build(threads, None)
+    }
+
+    fn with_queue_capacity(threads: u32, capacity: usize) -> Result<Self> {
+        RayonThreadPool::build(threads, Some(capacity))
     }
 
     fn spawn<F>(&self, job: F)
     where
-        F: FnOnce() + Sized + 'static,
+        F: FnOnce() + Send + 'static,
     {
-        todo!()
+        self.send(job, self.queue.reserve())
+    }
+
+    fn try_spawn<F>(&self, job: F) -> std::result::Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.queue.try_reserve() {
+            Some(slot) => {
+                self.send(job, slot);
+                Ok(())
+            }
+            None => Err(job),
+        }
+    }
+
+    fn metrics(&self) -> PoolMetrics {
+        self.metrics.clone()
+    }
+
+    fn shutdown(&self) {
+        self.jobs.stop();
+    }
+
+    fn join(self) {
+        self.shutdown();
+        self.jobs.wait_zero();
+        drop(self.pool);
+        self.threads.wait_zero();
     }
 }
@@ -1,17 +1,158 @@
-use super::ThreadPool;
-use crate::Result;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
-pub struct SharedQueueThreadPool {}
+use crossbeam::channel::{self, Receiver, Sender};
+use crossbeam::sync::WaitGroup;
+use log::{error, warn};
+
+use super::queue::{Queue, Slot};
+use super::{panic_message, PoolMetrics, ThreadPool};
+use crate::{KvsError, Result};
+
+/// A job together with the number it was spawned as, to tell it apart in logs.
+struct Job {
+    id: u64,
+    run: Box<dyn FnOnce() + Send + 'static>,
+}
+
+/// A fixed number of threads taking jobs from one shared queue.
+///
+/// A worker whose job panics is replaced, so panics do not shrink the pool.
+pub struct SharedQueueThreadPool {
+    /// Taken on shutdown, which ends the workers once the queue is empty
+    sender: Mutex<Option<Sender<Job>>>,
+    next_id: AtomicU64,
+    /// Held by every worker until it exits
+    workers: WaitGroup,
+    queue: Arc<Queue>,
+    metrics: PoolMetrics,
+}
+
+impl SharedQueueThreadPool {
+    fn build(threads: u32, capacity: Option<usize>) -> Result<Self> {
+        if threads == 0 {
+            return Err(KvsError::StringErr(
+                "Invalid shared queue pool size: 0 workers".to_owned(),
+            ));
+        }
+        let (sender, receiver) = channel::unbounded();
+        let workers = WaitGroup::new();
+        let metrics = PoolMetrics::default();
+        for worker in 0..threads {
+            spawn_worker(worker, receiver.clone(), workers.clone(), metrics.clone())?;
+        }
+        Ok(SharedQueueThreadPool {
+            sender: Mutex::new(Some(sender)),
+            next_id: AtomicU64::new(0),
+            workers,
+            queue: Queue::new(capacity),
+            metrics,
+        })
+    }
+
+    fn send<F>(&self, job: F, slot: Slot)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match &*self.sender.lock().unwrap() {
+            Some(sender) => {
+                let job = Job {
+                    id: self.next_id.fetch_add(1, Ordering::Relaxed),
+                    run: Box::new(self.metrics.queue(move || {
+                        drop(slot);
+                        job()
+                    })),
+                };
+                // The workers only stop once the sender is gone, unless the last
+                // of them could not be replaced after a panic.
+                if let Err(channel::SendError(job)) = sender.send(job) {
+                    error!(
+                        "Dropping job {}: the thread pool has no workers left",
+                        job.id
+                    );
+                    self.metrics.unqueue();
+                }
+            }
+            None => warn!("Dropping a job spawned after the thread pool was shut down"),
+        }
+    }
+}
 
 impl ThreadPool for SharedQueueThreadPool {
     fn new(threads: u32) -> Result<Self> {
-        todo!()
+        SharedQueueThreadPool::build(threads, None)
+    }
+
+    fn with_queue_capacity(threads: u32, capacity: usize) -> Result<Self> {
+        SharedQueueThreadPool::build(threads, Some(capacity))
     }
 
     fn spawn<F>(&self, job: F)
     where
-        F: FnOnce() + Sized + 'static,
+        F: FnOnce() + Send + 'static,
     {
-        todo!()
+        self.send(job, self.queue.reserve())
+    }
+
+    fn try_spawn<F>(&self, job: F) -> std::result::Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.queue.try_reserve() {
+            Some(slot) => {
+                self.send(job, slot);
+                Ok(())
+            }
+            None => Err(job),
+        }
+    }
+
+    fn metrics(&self) -> PoolMetrics {
+        self.metrics.clone()
+    }
+
+    fn shutdown(&self) {
+        self.sender.lock().unwrap().take();
+    }
+
+    fn join(self) {
+        self.shutdown();
+        self.workers.wait();
+    }
+}
+
+fn spawn_worker(
+    worker: u32,
+    receiver: Receiver<Job>,
+    running: WaitGroup,
+    metrics: PoolMetrics,
+) -> Result<()> {
+    thread::Builder::new()
+        .name(format!("shared-queue-worker-{}", worker))
+        .spawn(move || run_worker(worker, receiver, running, metrics))?;
+    Ok(())
+}
+
+/// Runs jobs until the pool is shut down and the queue is empty, or until a job
+/// panics and the worker hands its place to a new thread.
+fn run_worker(worker: u32, receiver: Receiver<Job>, running: WaitGroup, metrics: PoolMetrics) {
+    let _worker = metrics.worker();
+    for job in receiver.iter() {
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job.run)) {
+            error!(
+                "Job {} panicked on shared-queue-worker-{}: {}",
+                job.id,
+                worker,
+                panic_message(&*payload)
+            );
+            // The job may have left thread locals in a bad state, so the thread
+            // is not reused.
+            if let Err(e) = spawn_worker(worker, receiver, running, metrics.clone()) {
+                error!("Failed to replace shared-queue-worker-{}: {}", worker, e);
+            }
+            return;
+        }
     }
 }
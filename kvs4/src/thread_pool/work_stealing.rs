@@ -0,0 +1,235 @@
+use std::cell::RefCell;
+use std::iter;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use crossbeam::deque::{Injector, Steal, Stealer, Worker};
+use crossbeam::sync::WaitGroup;
+use log::{error, warn};
+
+use super::queue::{Queue, Slot};
+use super::{panic_message, PoolMetrics, ThreadPool};
+use crate::Result;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Tells the pools apart in the thread locals of their workers.
+static NEXT_POOL_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The deque of the worker running on this thread, if any.
+    static LOCAL: RefCell<Option<Local>> = const { RefCell::new(None) };
+}
+
+struct Local {
+    pool: usize,
+    deque: Worker<Job>,
+}
+
+/// A fixed number of threads, each with a deque of its own.
+///
+/// Jobs spawned from outside go to a global queue, and jobs spawned by a job to the
+/// deque of its worker, so they run on the same thread unless another worker runs out
+/// of jobs and steals them. A worker whose job panics is replaced and its deque handed
+/// to the new thread.
+pub struct WorkStealingThreadPool {
+    shared: Arc<Shared>,
+    /// Held by every worker until it exits
+    workers: WaitGroup,
+    queue: Arc<Queue>,
+}
+
+struct Shared {
+    id: usize,
+    injector: Injector<Job>,
+    stealers: Vec<Stealer<Job>>,
+    /// Set on shutdown. Jobs are pushed with it locked, so a worker that finds no job
+    /// with it locked can sleep without missing one.
+    stopped: Mutex<bool>,
+    job_pushed: Condvar,
+    metrics: PoolMetrics,
+}
+
+impl WorkStealingThreadPool {
+    fn build(threads: u32, capacity: Option<usize>) -> Result<Self> {
+        let deques: Vec<_> = (0..threads).map(|_| Worker::new_fifo()).collect();
+        let shared = Arc::new(Shared {
+            id: NEXT_POOL_ID.fetch_add(1, Ordering::Relaxed),
+            injector: Injector::new(),
+            stealers: deques.iter().map(Worker::stealer).collect(),
+            stopped: Mutex::new(false),
+            job_pushed: Condvar::new(),
+            metrics: PoolMetrics::default(),
+        });
+        // dropped on error, which stops the threads started so far
+        let pool = WorkStealingThreadPool {
+            shared,
+            workers: WaitGroup::new(),
+            queue: Queue::new(capacity),
+        };
+        for (worker, deque) in deques.into_iter().enumerate() {
+            spawn_worker(
+                worker,
+                deque,
+                Arc::clone(&pool.shared),
+                pool.workers.clone(),
+            )?;
+        }
+        Ok(pool)
+    }
+
+    fn send<F>(&self, job: F, slot: Slot)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let stopped = self.shared.stopped.lock().unwrap();
+        if *stopped {
+            warn!("Dropping a job spawned after the thread pool was shut down");
+            return;
+        }
+        let job: Job = Box::new(self.shared.metrics.queue(move || {
+            drop(slot);
+            job()
+        }));
+        let job = LOCAL.with(|local| match &*local.borrow() {
+            Some(local) if local.pool == self.shared.id => {
+                local.deque.push(job);
+                None
+            }
+            _ => Some(job),
+        });
+        if let Some(job) = job {
+            self.shared.injector.push(job);
+        }
+        // wakes a worker to take the job, or to steal it from a busy one
+        self.shared.job_pushed.notify_one();
+    }
+}
+
+impl ThreadPool for WorkStealingThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        WorkStealingThreadPool::build(threads, None)
+    }
+
+    fn with_queue_capacity(threads: u32, capacity: usize) -> Result<Self> {
+        WorkStealingThreadPool::build(threads, Some(capacity))
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.send(job, self.queue.reserve())
+    }
+
+    fn try_spawn<F>(&self, job: F) -> std::result::Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.queue.try_reserve() {
+            Some(slot) => {
+                self.send(job, slot);
+                Ok(())
+            }
+            None => Err(job),
+        }
+    }
+
+    fn metrics(&self) -> PoolMetrics {
+        self.shared.metrics.clone()
+    }
+
+    fn shutdown(&self) {
+        *self.shared.stopped.lock().unwrap() = true;
+        self.shared.job_pushed.notify_all();
+    }
+
+    fn join(self) {
+        self.shutdown();
+        let workers = self.workers.clone();
+        drop(self);
+        workers.wait();
+    }
+}
+
+impl Drop for WorkStealingThreadPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl Shared {
+    /// Takes a job from `deque`, or else from the global queue or another worker.
+    fn find_job(&self, deque: &Worker<Job>) -> Option<Job> {
+        deque.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injector
+                    .steal_batch_and_pop(deque)
+                    .or_else(|| self.stealers.iter().map(Stealer::steal).collect())
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(Steal::success)
+        })
+    }
+
+    fn has_jobs(&self) -> bool {
+        !self.injector.is_empty() || self.stealers.iter().any(|stealer| !stealer.is_empty())
+    }
+}
+
+fn spawn_worker(
+    worker: usize,
+    deque: Worker<Job>,
+    shared: Arc<Shared>,
+    running: WaitGroup,
+) -> Result<()> {
+    thread::Builder::new()
+        .name(format!("work-stealing-worker-{}", worker))
+        .spawn(move || run_worker(worker, deque, shared, running))?;
+    Ok(())
+}
+
+/// Runs jobs until the pool is shut down and no job is left, or until a job panics
+/// and the worker hands its place to a new thread.
+fn run_worker(worker: usize, deque: Worker<Job>, shared: Arc<Shared>, running: WaitGroup) {
+    let _worker = shared.metrics.worker();
+    LOCAL.with(|local| {
+        *local.borrow_mut() = Some(Local {
+            pool: shared.id,
+            deque,
+        })
+    });
+    loop {
+        let job = LOCAL.with(|local| shared.find_job(&local.borrow().as_ref().unwrap().deque));
+        let job = match job {
+            Some(job) => job,
+            None => {
+                let stopped = shared.stopped.lock().unwrap();
+                if shared.has_jobs() {
+                    continue;
+                }
+                if *stopped {
+                    return;
+                }
+                drop(shared.job_pushed.wait(stopped).unwrap());
+                continue;
+            }
+        };
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+            error!(
+                "Job panicked on work-stealing-worker-{}: {}",
+                worker,
+                panic_message(&*payload)
+            );
+            // The job may have left thread locals in a bad state, so the thread
+            // is not reused.
+            let deque = LOCAL.with(|local| local.borrow_mut().take().unwrap().deque);
+            if let Err(e) = spawn_worker(worker, deque, Arc::clone(&shared), running) {
+                error!("Failed to replace work-stealing-worker-{}: {}", worker, e);
+            }
+            return;
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use kvs::{
+    Capacity, EvictionPolicy, KvStore, KvStoreOptions, KvsEngine, MemStore, Result, SledStore,
+};
+use std::path::Path;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn max_keys(max: u64, policy: EvictionPolicy) -> Capacity {
+    Capacity {
+        max_keys: Some(max),
+        max_bytes: None,
+        policy,
+    }
+}
+
+fn open_kvs(path: &Path, capacity: Capacity) -> Result<KvStore> {
+    let options = KvStoreOptions {
+        capacity,
+        ..KvStoreOptions::default()
+    };
+    KvStore::open_with(path, options)
+}
+
+fn open_sled(path: &Path, capacity: Capacity) -> Result<SledStore> {
+    SledStore::with_capacity(sled::open(path)?, capacity)
+}
+
+fn open_mem(_: &Path, capacity: Capacity) -> Result<MemStore> {
+    Ok(MemStore::with_capacity(capacity))
+}
+
+fn keys<E: KvsEngine>(engine: &E) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    engine.scan(|key, _| {
+        keys.push(key);
+        Ok(())
+    })?;
+    Ok(keys)
+}
+
+// Sets key1 and key2, reads key2 once and then key1 three times, and sets key3
+// into a store that holds two keys.
+fn evicted_by<E: KvsEngine>(
+    open: fn(&Path, Capacity) -> Result<E>,
+    policy: EvictionPolicy,
+) -> Result<Vec<String>> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path(), max_keys(2, policy))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.get("key2".to_owned())?;
+    for _ in 0..3 {
+        store.get("key1".to_owned())?;
+    }
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    let keys = keys(&store)?;
+    assert_eq!(keys.len(), 2);
+    // the key written last stays
+    assert!(keys.contains(&"key3".to_owned()));
+    assert_eq!(store.stats()?.evictions, 1);
+    Ok(["key1", "key2"]
+        .iter()
+        .filter(|key| !keys.contains(&key.to_string()))
+        .map(|key| key.to_string())
+        .collect())
+}
+
+fn policies<E: KvsEngine>(open: fn(&Path, Capacity) -> Result<E>) -> Result<()> {
+    assert_eq!(evicted_by(open, EvictionPolicy::Lru)?, vec!["key2"]);
+    assert_eq!(evicted_by(open, EvictionPolicy::Lfu)?, vec!["key2"]);
+    // reads don't move a key back
+    assert_eq!(evicted_by(open, EvictionPolicy::Fifo)?, vec!["key1"]);
+    assert_eq!(evicted_by(open, EvictionPolicy::Random)?.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn kvs_eviction_policies() -> Result<()> {
+    policies(open_kvs)
+}
+
+#[test]
+fn sled_eviction_policies() -> Result<()> {
+    policies(open_sled)
+}
+
+#[test]
+fn mem_eviction_policies() -> Result<()> {
+    policies(open_mem)
+}
+
+// Evictions are logged as removes, so evicted keys stay gone after a restart,
+// and a smaller capacity takes effect on open. `open` opens the same data again.
+fn evictions_survive_restart<E, F>(mut open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: FnMut(Capacity) -> Result<E>,
+{
+    let store = open(max_keys(3, EvictionPolicy::Lru))?;
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    assert_eq!(keys(&store)?, vec!["key2", "key3", "key4"]);
+    drop(store);
+
+    let store = open(Capacity::default())?;
+    assert_eq!(keys(&store)?, vec!["key2", "key3", "key4"]);
+    assert_eq!(store.stats()?.keys, Some(3));
+    drop(store);
+
+    // without access history, keys go in the order they are stored in
+    let store = open(max_keys(1, EvictionPolicy::Lru))?;
+    assert_eq!(keys(&store)?, vec!["key4"]);
+    assert_eq!(store.stats()?.evictions, 2);
+    drop(store);
+    let store = open(Capacity::default())?;
+    assert_eq!(keys(&store)?, vec!["key4"]);
+    Ok(())
+}
+
+#[test]
+fn kvs_evictions_survive_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    evictions_survive_restart(|capacity| open_kvs(temp_dir.path(), capacity))
+}
+
+#[test]
+fn sled_evictions_survive_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // sled gives up its lock on the directory in the background once the last
+    // handle is dropped, so opening it again right away may fail. The stores share
+    // a handle instead, and each one still starts from what is in the tree.
+    let db = sled::open(temp_dir.path())?;
+    evictions_survive_restart(|capacity| SledStore::with_capacity(db.clone(), capacity))
+}
+
+#[test]
+fn kvs_max_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_kvs(
+        temp_dir.path(),
+        Capacity {
+            max_keys: None,
+            max_bytes: Some(1000),
+            policy: EvictionPolicy::Lru,
+        },
+    )?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "v".repeat(50))?;
+    }
+    // every record takes about 80 bytes of log
+    let keys = keys(&store)?;
+    assert!(!keys.is_empty() && keys.len() <= 12);
+    assert!(keys.contains(&"key99".to_owned()));
+    Ok(())
+}
+
+// Keys that expire sooner go first, and keys without a TTL go last, in the order
+// they were written. Reads change nothing.
+fn ttl_first<E: KvsEngine>(open: fn(&Path, Capacity) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path(), max_keys(3, EvictionPolicy::TtlFirst))?;
+    let hour = Duration::from_secs(3600);
+    store.set("forever1".to_owned(), "value".to_owned())?;
+    store.set_with_ttl("later".to_owned(), "value".to_owned(), 2 * hour)?;
+    store.set_with_ttl("sooner".to_owned(), "value".to_owned(), hour)?;
+    for key in &["forever1", "later", "sooner"] {
+        store.get(key.to_string())?;
+    }
+
+    store.set("forever2".to_owned(), "value".to_owned())?;
+    assert_eq!(keys(&store)?, vec!["forever1", "forever2", "later"]);
+    store.set("forever3".to_owned(), "value".to_owned())?;
+    assert_eq!(keys(&store)?, vec!["forever1", "forever2", "forever3"]);
+    store.get("forever1".to_owned())?;
+    store.set("forever4".to_owned(), "value".to_owned())?;
+    assert_eq!(keys(&store)?, vec!["forever2", "forever3", "forever4"]);
+    assert_eq!(store.stats()?.evictions, 3);
+    Ok(())
+}
+
+#[test]
+fn kvs_ttl_first() -> Result<()> {
+    ttl_first(open_kvs)
+}
+
+#[test]
+fn sled_ttl_first() -> Result<()> {
+    ttl_first(open_sled)
+}
+
+#[test]
+fn mem_ttl_first() -> Result<()> {
+    ttl_first(open_mem)
+}
+
+// The expiries are read back from the log when a store is opened.
+#[test]
+fn kvs_ttl_first_after_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let hour = Duration::from_secs(3600);
+    store.set_with_ttl("sooner".to_owned(), "value".to_owned(), hour)?;
+    store.set("forever".to_owned(), "value".to_owned())?;
+    store.set_with_ttl("later".to_owned(), "value".to_owned(), 2 * hour)?;
+    drop(store);
+
+    let store = open_kvs(temp_dir.path(), max_keys(1, EvictionPolicy::TtlFirst))?;
+    assert_eq!(keys(&store)?, vec!["forever"]);
+    Ok(())
+}
@@ -1,5 +1,6 @@
 use assert_cmd::prelude::*;
-use predicates::str::{contains, is_empty};
+use kvs::{DirLock, KvStore, KvsEngine, SledStore};
+use predicates::str::{contains, is_empty, starts_with};
 use std::fs::{self, File};
 use std::process::Command;
 use std::sync::mpsc;
@@ -129,6 +130,23 @@ fn client_cli_invalid_subcommand() {
         .failure();
 }
 
+#[test]
+fn server_cli_invalid_pool() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--pool", "unknown"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--threads", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
 // `kvs-client -V` should print the version
 #[test]
 fn client_cli_version() {
@@ -291,6 +309,30 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["incr", "counter", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["incr", "counter", "-5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("-4\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["incr", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not an integer"));
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
@@ -335,3 +377,273 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_lsm_engine() {
+    cli_access_server("lsm", "127.0.0.1:4007");
+}
+
+// The memory engine evicts beyond its capacity and leaves the directory alone.
+#[test]
+fn cli_memory_engine() {
+    let addr = "127.0.0.1:4008";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "memory", "--max-keys", "2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for key in &["key1", "key2", "key3"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", key, "value", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(starts_with(
+            "keys 2\nevictions 1\nhealth ok\nactive-workers ",
+        ))
+        .stdout(contains("\nqueue-depth 0\n"))
+        .stdout(contains("\njobs-panicked 0\n"))
+        .stdout(contains("\nqueue-wait >=1s "));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert!(!temp_dir.path().join("engine").exists());
+}
+
+// A bulk load that fails leaves no store behind, so it can be retried.
+#[test]
+fn cli_restore_bulk_failure() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let dump = temp_dir.path().join("dump.json");
+    fs::write(&dump, "{\"key\":\"key1\",\"value\":\"value1\"}\nnot json\n").unwrap();
+
+    Command::cargo_bin("kvs-restore")
+        .unwrap()
+        .args(&["--bulk", "--dir"])
+        .arg(&data_dir)
+        .arg(&dump)
+        .assert()
+        .failure();
+    assert!(!data_dir.exists());
+
+    fs::write(&dump, "{\"key\":\"key1\",\"value\":\"value1\"}\n").unwrap();
+    Command::cargo_bin("kvs-restore")
+        .unwrap()
+        .args(&["--bulk", "--dir"])
+        .arg(&data_dir)
+        .arg(&dump)
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(data_dir.join("engine")).unwrap(), "kvs");
+    let store = KvStore::open(&data_dir).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[test]
+fn cli_admin_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    {
+        let store = KvStore::open(&data_dir).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+        fs::write(data_dir.join("engine"), "kvs").unwrap();
+    }
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "sled", "--to", "kvs"])
+        .arg(&data_dir)
+        .assert()
+        .failure();
+
+    // refused while a server holds the directory
+    let lock = DirLock::acquire(&data_dir).unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled"])
+        .arg(&data_dir)
+        .assert()
+        .failure()
+        .stderr(contains("is in use by process"));
+    drop(lock);
+
+    let logs = |dir: &std::path::Path| {
+        let mut logs: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| name.to_string_lossy().ends_with(".log"))
+            .collect();
+        logs.sort();
+        logs
+    };
+    let source_logs = logs(&data_dir);
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled"])
+        .arg(&data_dir)
+        .assert()
+        .success();
+
+    assert_eq!(fs::read_to_string(data_dir.join("engine")).unwrap(), "sled");
+    // the source was only read
+    assert_eq!(logs(&temp_dir.path().join("data.kvs-backup")), source_logs);
+    let db = SledStore::new(sled::open(&data_dir).unwrap());
+    assert_eq!(
+        db.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        db.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+}
+
+// An interrupted swap is finished whatever form of the path is given.
+#[test]
+fn cli_admin_migrate_finishes_interrupted_swap() {
+    let temp_dir = TempDir::new().unwrap();
+    let root = temp_dir.path().canonicalize().unwrap();
+    let staging = root.join("data.migrating-sled");
+    let backup = root.join("data.kvs-backup");
+    fs::create_dir(&backup).unwrap();
+    fs::write(backup.join("engine"), "kvs").unwrap();
+    fs::create_dir(&staging).unwrap();
+    fs::write(staging.join("engine"), "sled").unwrap();
+    fs::write(
+        root.join("data.swap"),
+        format!("{}\n{}\n", staging.display(), backup.display()),
+    )
+    .unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled", "./data"])
+        .current_dir(&root)
+        .assert()
+        .success()
+        .stdout(contains("finished the interrupted migration"));
+    assert_eq!(
+        fs::read_to_string(root.join("data/engine")).unwrap(),
+        "sled"
+    );
+    assert!(!root.join("data.swap").exists());
+}
+
+#[test]
+fn cli_watch() {
+    let addr = "127.0.0.1:4006";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let stdout_path = temp_dir.path().join("stdout");
+    let mut watcher = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["watch", "user:", "--addr", addr])
+        .current_dir(&temp_dir)
+        .stdout(File::create(&stdout_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let commands: &[&[&str]] = &[
+        &["set", "user:1", "alice"],
+        &["set", "other", "ignored"],
+        &["set", "user:2", "bob"],
+        &["rm", "user:1"],
+    ];
+    for args in commands {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(*args)
+            .args(&["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    thread::sleep(Duration::from_millis(500));
+    watcher.kill().expect("watcher exited before killed");
+    watcher.wait().unwrap();
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+
+    let content = fs::read_to_string(&stdout_path).expect("unable to read from stdout file");
+    assert_eq!(
+        content,
+        "1 set user:1 alice\n3 set user:2 bob\n4 rm user:1\n"
+    );
+}
+
+// SIGTERM stops the server cleanly, so the store is closed as on a normal exit.
+#[test]
+fn cli_graceful_shutdown() {
+    let addr = "127.0.0.1:4013";
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::new("kill")
+        .args(&["-TERM", &server.id().to_string()])
+        .assert()
+        .success();
+    assert!(server.wait().unwrap().success());
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("Shutting down"));
+    // the index snapshot is only written once the store is closed
+    assert!(temp_dir.path().join("index.snapshot").exists());
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
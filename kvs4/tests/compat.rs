@@ -0,0 +1,105 @@
+use kvs::{KvStore, KvsEngine, Result, StoreFormat};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+// Copies a fixture into a temporary directory so that upgrading it leaves the
+// original untouched.
+fn open_fixture(name: &str) -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join(name);
+    for entry in fs::read_dir(fixture).expect("fixture not found") {
+        let entry = entry.unwrap();
+        fs::copy(entry.path(), temp_dir.path().join(entry.file_name())).unwrap();
+    }
+    temp_dir
+}
+
+fn log_count(path: &Path) -> usize {
+    WalkDir::new(path)
+        .into_iter()
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .map(|e| e.path().extension() == Some("log".as_ref()))
+                .unwrap_or(false)
+        })
+        .count()
+}
+
+// Both fixtures were produced by the same sequence of commands:
+// set key1, set key2, set key1 (quoted value), rm key2, set a unicode key, set key3.
+fn check_fixture_content(store: &KvStore) -> Result<()> {
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("value with \"quotes\"".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(
+        store.get("unicode ключ".to_owned())?,
+        Some("значение ✓".to_owned())
+    );
+    Ok(())
+}
+
+fn upgrade_fixture(name: &str, format: StoreFormat) -> Result<()> {
+    let temp_dir = open_fixture(name);
+    assert_eq!(KvStore::detect_format(temp_dir.path())?, format);
+    assert!(format.needs_upgrade());
+
+    let store = KvStore::open(temp_dir.path())?;
+    check_fixture_content(&store)?;
+    assert_eq!(
+        KvStore::detect_format(temp_dir.path())?,
+        StoreFormat::Versioned(1)
+    );
+    // the compaction generation and the new active generation
+    assert_eq!(log_count(temp_dir.path()), 2);
+
+    // Writes after the upgrade survive a reopen
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check_fixture_content(&store)?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// Data written by the single-threaded `kvs` CLI of kvs2
+#[test]
+fn upgrade_kvs2_cli_store() -> Result<()> {
+    upgrade_fixture("kvs2", StoreFormat::LegacyCli)
+}
+
+// Data written by the kvs3 `kvs-server`
+#[test]
+fn upgrade_kvs3_server_store() -> Result<()> {
+    upgrade_fixture("kvs3", StoreFormat::LegacyServer)
+}
+
+#[test]
+fn new_store_records_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(KvStore::detect_format(temp_dir.path())?, StoreFormat::Empty);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert_eq!(
+        KvStore::detect_format(temp_dir.path())?,
+        StoreFormat::Versioned(1)
+    );
+    Ok(())
+}
+
+#[test]
+fn refuse_newer_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("format"), "2\n")?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use kvs::{
+    dump, restore, DumpFormat, DumpReader, KvStore, KvStoreFollower, KvsEngine, KvsError, Result,
+    SledStore,
+};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn fill<E: KvsEngine>(engine: &E) -> Result<()> {
+    for i in 0..100 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine.set("quoted\"key".to_owned(), "multi\nline\tvalue".to_owned())?;
+    engine.remove("key0".to_owned())?;
+    Ok(())
+}
+
+fn check<E: KvsEngine>(engine: &E) -> Result<()> {
+    for i in 1..100 {
+        assert_eq!(engine.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(engine.get("key0".to_owned())?, None);
+    assert_eq!(
+        engine.get("quoted\"key".to_owned())?,
+        Some("multi\nline\tvalue".to_owned())
+    );
+    Ok(())
+}
+
+fn round_trip(format: DumpFormat) -> Result<()> {
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(kvs_dir.path())?;
+    fill(&store)?;
+
+    let mut buf = Vec::new();
+    assert_eq!(dump(&store, &mut buf, format)?, 100);
+
+    let db = SledStore::new(sled::open(sled_dir.path())?);
+    assert_eq!(restore(&db, &buf[..], format)?, 100);
+    check(&db)?;
+
+    // And back again
+    let mut buf = Vec::new();
+    assert_eq!(dump(&db, &mut buf, format)?, 100);
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(kvs_dir.path())?;
+    assert_eq!(restore(&store, &buf[..], format)?, 100);
+    check(&store)
+}
+
+#[test]
+fn json_lines_round_trip() -> Result<()> {
+    round_trip(DumpFormat::JsonLines)
+}
+
+#[test]
+fn binary_round_trip() -> Result<()> {
+    round_trip(DumpFormat::Binary)
+}
+
+#[test]
+fn binary_rejects_bad_header() {
+    assert!(DumpReader::new(&b"not a dump"[..], DumpFormat::Binary).is_err());
+}
+
+#[test]
+fn bulk_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = KvStore::open(temp_dir.path())?;
+    fill(&source)?;
+    let mut buf = Vec::new();
+    dump(&source, &mut buf, DumpFormat::Binary)?;
+
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::bulk_load(
+        target_dir.path(),
+        DumpReader::new(&buf[..], DumpFormat::Binary)?,
+    )?;
+    check(&store)?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(target_dir.path())?;
+    check(&store)?;
+
+    // Bulk load refuses to overwrite existing data
+    assert!(KvStore::bulk_load(target_dir.path(), Vec::new()).is_err());
+    Ok(())
+}
+
+fn list_dir(path: &Path) -> Vec<(String, u64)> {
+    let mut entries: Vec<_> = fs::read_dir(path)
+        .unwrap()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            let len = entry.metadata().unwrap().len();
+            (entry.file_name().to_string_lossy().into_owned(), len)
+        })
+        .collect();
+    entries.sort();
+    entries
+}
+
+// Dumping through a follower leaves the store directory as it was
+#[test]
+fn dump_does_not_modify_kvs_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    fill(&store)?;
+    drop(store);
+
+    let before = list_dir(temp_dir.path());
+    let mut buf = Vec::new();
+    let count = dump(
+        &KvStoreFollower::open(temp_dir.path())?,
+        &mut buf,
+        DumpFormat::JsonLines,
+    )?;
+    assert_eq!(count, 100);
+    assert_eq!(list_dir(temp_dir.path()), before);
+
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(target_dir.path())?;
+    restore(&store, &buf[..], DumpFormat::JsonLines)?;
+    check(&store)?;
+    Ok(())
+}
+
+// A bulk load whose input fails can be retried in the same directory
+#[test]
+fn bulk_load_cleans_up_on_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let entries = vec![
+        Ok(("key1".to_owned(), "value1".to_owned())),
+        Err(KvsError::InvalidDump("truncated".to_owned())),
+    ];
+    assert!(KvStore::bulk_load(temp_dir.path(), entries).is_err());
+    assert!(list_dir(temp_dir.path()).is_empty());
+
+    let store = KvStore::bulk_load(
+        temp_dir.path(),
+        (1..100)
+            .map(|i| (format!("key{}", i), format!("value{}", i)))
+            .chain(Some((
+                "quoted\"key".to_owned(),
+                "multi\nline\tvalue".to_owned(),
+            )))
+            .map(Ok),
+    )?;
+    check(&store)?;
+
+    // A directory created by the load is removed again
+    let target = temp_dir.path().join("nested");
+    assert!(KvStore::bulk_load(
+        &target,
+        vec![Err(KvsError::InvalidDump("truncated".to_owned()))]
+    )
+    .is_err());
+    assert!(!target.exists());
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use kvs::{KvStore, KvStoreFollower, KvsEngine, Result};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use tempfile::TempDir;
+
+// A follower sees appends of the writer once it refreshes.
+#[test]
+fn follow_appends() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut follower = KvStoreFollower::open(temp_dir.path())?;
+    assert_eq!(follower.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(follower.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(follower.get("key3".to_owned())?, None);
+
+    follower.refresh()?;
+    assert_eq!(follower.get("key1".to_owned())?, None);
+    assert_eq!(follower.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // a follower does not touch the directory, so the writer reopens it as before
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    follower.refresh()?;
+    let mut entries = Vec::new();
+    follower.scan(|key, value| {
+        entries.push((key, value));
+        Ok(())
+    })?;
+    assert_eq!(
+        entries,
+        vec![
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key3".to_owned(), "value3".to_owned()),
+            ("key4".to_owned(), "value4".to_owned()),
+        ]
+    );
+    Ok(())
+}
+
+// Compactions of the writer replace generations under the follower, which must
+// keep up with whatever happens between two refreshes.
+#[test]
+fn follow_compactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut follower = KvStoreFollower::open(temp_dir.path())?;
+
+    let dir_size = || {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum::<u64>()
+    };
+    let mut compactions = 0;
+    let mut last_size = 0;
+    for iter in 0..1000 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        if iter % 7 == 0 {
+            follower.refresh()?;
+            for key_id in 0..100 {
+                assert_eq!(
+                    follower.get(format!("key{}", key_id))?,
+                    Some(format!("{}", iter))
+                );
+            }
+        }
+        let new_size = dir_size();
+        if new_size < last_size {
+            compactions += 1;
+        }
+        last_size = new_size;
+    }
+    assert!(compactions > 0);
+
+    follower.refresh()?;
+    for key_id in 0..100 {
+        assert_eq!(
+            follower.get(format!("key{}", key_id))?,
+            Some("999".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// A record the writer is still appending is left for a later refresh.
+#[test]
+fn skip_partial_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("1.log"))?;
+    log.write_all(br#"{"Set":{"key":"key2","val"#)?;
+    log.flush()?;
+
+    let mut follower = KvStoreFollower::open(temp_dir.path())?;
+    assert_eq!(follower.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(follower.get("key2".to_owned())?, None);
+
+    log.write_all(br#"ue":"value2"}}"#)?;
+    log.flush()?;
+    follower.refresh()?;
+    assert_eq!(follower.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn open_missing_directory() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(KvStoreFollower::open(temp_dir.path().join("missing")).is_err());
+    assert!(!temp_dir.path().join("missing").exists());
+}
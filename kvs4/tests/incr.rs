@@ -0,0 +1,85 @@
+use kvs::{KvStore, KvsEngine, LsmStore, MemStore, Result, SledStore};
+use std::thread;
+use tempfile::TempDir;
+
+fn incr_and_decr<E: KvsEngine>(engine: E) -> Result<()> {
+    assert_eq!(engine.incr_by("counter".to_owned(), 5)?, 5);
+    assert_eq!(engine.incr_by("counter".to_owned(), -7)?, -2);
+    assert_eq!(engine.decr_by("counter".to_owned(), 3)?, -5);
+    assert_eq!(engine.get("counter".to_owned())?, Some("-5".to_owned()));
+
+    engine.set("name".to_owned(), "alice".to_owned())?;
+    assert!(engine.incr_by("name".to_owned(), 1).is_err());
+    assert_eq!(engine.get("name".to_owned())?, Some("alice".to_owned()));
+
+    engine.set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(engine.incr_by("max".to_owned(), 1).is_err());
+    assert_eq!(engine.get("max".to_owned())?, Some(i64::MAX.to_string()));
+    Ok(())
+}
+
+// Concurrent increments must not lose updates.
+fn concurrent_incr<E: KvsEngine>(engine: E) -> Result<()> {
+    const THREADS: i64 = 8;
+    const INCRS: i64 = 100;
+
+    let handles: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..INCRS {
+                    engine.incr_by("counter".to_owned(), 1)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(
+        engine.get("counter".to_owned())?,
+        Some((THREADS * INCRS).to_string())
+    );
+    Ok(())
+}
+
+#[test]
+fn kvs_incr_and_decr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    incr_and_decr(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_incr_and_decr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    incr_and_decr(SledStore::new(sled::open(temp_dir.path())?))
+}
+
+#[test]
+fn lsm_incr_and_decr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    incr_and_decr(LsmStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn mem_incr_and_decr() -> Result<()> {
+    incr_and_decr(MemStore::new())
+}
+
+#[test]
+fn kvs_concurrent_incr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_incr(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_concurrent_incr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_incr(SledStore::new(sled::open(temp_dir.path())?))
+}
+
+#[test]
+fn mem_concurrent_incr() -> Result<()> {
+    concurrent_incr(MemStore::new())
+}
@@ -1,12 +1,45 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, LsmStore, Result};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+fn open_kvs(path: &Path) -> Result<KvStore> {
+    KvStore::open(path)
+}
+
+fn open_lsm(path: &Path) -> Result<LsmStore> {
+    LsmStore::open(path)
+}
+
+fn open_compact(path: &Path) -> Result<KvStore> {
+    let options = KvStoreOptions {
+        compact_index: true,
+        ..KvStoreOptions::default()
+    };
+    KvStore::open_with(path, options)
+}
+
 // Should get previously stored value
 #[test]
 fn get_stored_value() -> Result<()> {
+    get_stored_value_with(open_kvs)
+}
+
+#[test]
+fn lsm_get_stored_value() -> Result<()> {
+    get_stored_value_with(open_lsm)
+}
+
+#[test]
+fn compact_index_get_stored_value() -> Result<()> {
+    get_stored_value_with(open_compact)
+}
+
+fn get_stored_value_with<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -16,7 +49,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -26,8 +59,22 @@ fn get_stored_value() -> Result<()> {
 // Should overwrite existent value
 #[test]
 fn overwrite_value() -> Result<()> {
+    overwrite_value_with(open_kvs)
+}
+
+#[test]
+fn lsm_overwrite_value() -> Result<()> {
+    overwrite_value_with(open_lsm)
+}
+
+#[test]
+fn compact_index_overwrite_value() -> Result<()> {
+    overwrite_value_with(open_compact)
+}
+
+fn overwrite_value_with<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -36,7 +83,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -47,15 +94,29 @@ fn overwrite_value() -> Result<()> {
 // Should get `None` when getting a non-existent key
 #[test]
 fn get_non_existent_value() -> Result<()> {
+    get_non_existent_value_with(open_kvs)
+}
+
+#[test]
+fn lsm_get_non_existent_value() -> Result<()> {
+    get_non_existent_value_with(open_lsm)
+}
+
+#[test]
+fn compact_index_get_non_existent_value() -> Result<()> {
+    get_non_existent_value_with(open_compact)
+}
+
+fn get_non_existent_value_with<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -63,16 +124,44 @@ fn get_non_existent_value() -> Result<()> {
 
 #[test]
 fn remove_non_existent_key() -> Result<()> {
+    remove_non_existent_key_with(open_kvs)
+}
+
+#[test]
+fn lsm_remove_non_existent_key() -> Result<()> {
+    remove_non_existent_key_with(open_lsm)
+}
+
+#[test]
+fn compact_index_remove_non_existent_key() -> Result<()> {
+    remove_non_existent_key_with(open_compact)
+}
+
+fn remove_non_existent_key_with<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
 
 #[test]
 fn remove_key() -> Result<()> {
+    remove_key_with(open_kvs)
+}
+
+#[test]
+fn lsm_remove_key() -> Result<()> {
+    remove_key_with(open_lsm)
+}
+
+#[test]
+fn compact_index_remove_key() -> Result<()> {
+    remove_key_with(open_compact)
+}
+
+fn remove_key_with<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -83,8 +172,22 @@ fn remove_key() -> Result<()> {
 // Test data correctness after compaction.
 #[test]
 fn compaction() -> Result<()> {
+    compaction_with(open_kvs)
+}
+
+#[test]
+fn lsm_compaction() -> Result<()> {
+    compaction_with(open_lsm)
+}
+
+#[test]
+fn compact_index_compaction() -> Result<()> {
+    compaction_with(open_compact)
+}
+
+fn compaction_with<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -114,7 +217,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let mut store = open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -124,3 +227,216 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// A store can switch between a full and a compact index from one open to the next.
+#[test]
+fn switch_index_kind() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_compact(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    // from the snapshot of the compact index
+    let store = open_compact(temp_dir.path())?;
+    store.set("key1".to_owned(), "value".to_owned())?;
+    let mut keys = Vec::new();
+    store.scan(|key, _| {
+        keys.push(key);
+        Ok(())
+    })?;
+    keys.sort();
+    let mut expected: Vec<String> = (1..100).map(|key_id| format!("key{}", key_id)).collect();
+    expected.sort();
+    assert_eq!(keys, expected);
+    drop(store);
+
+    let store = open_kvs(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    drop(store);
+
+    let store = open_compact(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
+
+// Every open starts a new generation. Later generations overwrite and remove keys
+// of earlier ones, and a reopen must apply them in order.
+#[test]
+fn reopen_many_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for gen in 0..16 {
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("value{}", gen))?;
+        }
+        for key_id in (gen..200).step_by(16) {
+            store.remove(format!("key{}", key_id))?;
+        }
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..200 {
+        let expected = if key_id % 16 == 15 {
+            None
+        } else {
+            Some("value15".to_owned())
+        };
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    Ok(())
+}
+
+// A torn write at the end of a log should be reported and repaired.
+#[test]
+fn verify_and_repair_corrupt_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let intact_len = fs::metadata(&log)?.len();
+    OpenOptions::new()
+        .append(true)
+        .open(&log)?
+        .write_all(br#"{"Set":{"key":"key3","val"#)?;
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(!report.is_clean());
+    assert_eq!(report.generations[0].sets, 2);
+    assert_eq!(report.generations[0].removes, 1);
+    assert_eq!(report.generations[0].corrupt_offset, Some(intact_len));
+    assert_eq!(report.live_keys, 1);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let repair = KvStore::repair(temp_dir.path())?;
+    assert_eq!(repair.truncated, vec![(1, intact_len)]);
+    assert!(KvStore::verify(temp_dir.path())?.is_clean());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Files left behind by an interrupted compaction should be quarantined.
+#[test]
+fn repair_orphan_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    fs::write(temp_dir.path().join("3.compact"), br#"{"Set":{"key":"ke"#)?;
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(!report.is_clean());
+    assert_eq!(report.orphan_compactions.len(), 1);
+
+    let repair = KvStore::repair(temp_dir.path())?;
+    assert_eq!(repair.orphans_removed.len(), 1);
+    assert!(temp_dir.path().join("quarantine").join("3.compact").exists());
+    assert!(KvStore::verify(temp_dir.path())?.is_clean());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Write enough to flush several SSTables and merge them, then check every key
+// through the tables, with and without tombstones.
+#[test]
+fn lsm_flush_and_merge_tables() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmStore::open(temp_dir.path())?;
+    let value = "v".repeat(100);
+    for key_id in 0..40000 {
+        store.set(format!("key{}", key_id), format!("{}{}", value, key_id))?;
+    }
+    for key_id in (0..40000).step_by(3) {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    let tables = || {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("sst".as_ref()))
+            .count()
+    };
+    assert!(tables() > 0);
+    // four flushes of the same size end up in one table
+    assert!(tables() < 4);
+
+    drop(store);
+    let store = LsmStore::open(temp_dir.path())?;
+    for key_id in 0..40000 {
+        let expected = if key_id % 3 == 0 {
+            None
+        } else {
+            Some(format!("{}{}", value, key_id))
+        };
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    let mut live = 0;
+    store.scan(|_, _| {
+        live += 1;
+        Ok(())
+    })?;
+    assert_eq!(live, 40000 - 13334);
+    Ok(())
+}
+
+// Inputs of a compaction that are still around after a crash are obsolete, and
+// don't bring back keys whose tombstones the compaction dropped.
+#[test]
+fn lsm_ignores_leftover_compaction_inputs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let tables = || -> Vec<PathBuf> {
+        let mut tables: Vec<_> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("sst".as_ref()))
+            .collect();
+        tables.sort();
+        tables
+    };
+    let store = LsmStore::open(temp_dir.path())?;
+    let mut next_key = 0;
+    // writes until the tables on disk change, i.e. until the memtable is flushed
+    let mut flush = || -> Result<()> {
+        let before = tables();
+        while tables() == before {
+            store.set(format!("key{}", next_key), "v".repeat(100))?;
+            next_key += 1;
+        }
+        Ok(())
+    };
+
+    store.set("gone".to_owned(), "value".to_owned())?;
+    flush()?;
+    let first = tables().remove(0);
+    let saved = fs::read(&first)?;
+    store.remove("gone".to_owned())?;
+    // the fourth flush merges all tables, dropping the tombstone
+    for _ in 0..3 {
+        flush()?;
+    }
+    assert!(!tables().contains(&first));
+    drop(flush);
+    drop(store);
+
+    // as if the process died before the first input was deleted
+    fs::write(&first, saved)?;
+    let store = LsmStore::open(temp_dir.path())?;
+    assert_eq!(store.get("gone".to_owned())?, None);
+    assert!(!tables().contains(&first));
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use kvs::{Capacity, Event, EvictionPolicy, KvsEngine, MemStore, Result};
+
+#[test]
+fn get_set_remove() -> Result<()> {
+    let store = MemStore::new();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // clones share the same data
+    let clone = store.clone();
+    clone.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+// Once over `max_keys`, the least recently used key goes first.
+#[test]
+fn evict_least_recently_used_key() -> Result<()> {
+    let store = MemStore::with_capacity(Capacity {
+        max_keys: Some(2),
+        max_bytes: None,
+        policy: EvictionPolicy::Lru,
+    });
+    let subscription = store.subscribe(String::new())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    // reading key1 makes key2 the least recently used
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // subscribers see the eviction as a remove
+    let events: Vec<Event> = subscription.take(4).collect();
+    match &events[3] {
+        Event::Remove { key, .. } => assert_eq!(key, "key2"),
+        event => panic!("unexpected event {:?}", event),
+    }
+    Ok(())
+}
+
+#[test]
+fn evict_to_max_bytes() -> Result<()> {
+    let store = MemStore::with_capacity(Capacity {
+        max_keys: None,
+        max_bytes: Some(100),
+        policy: EvictionPolicy::Lru,
+    });
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "v".repeat(20))?;
+    }
+    // every entry takes 24 bytes, so only the last four fit
+    let mut keys = Vec::new();
+    store.scan(|key, _| {
+        keys.push(key);
+        Ok(())
+    })?;
+    assert_eq!(keys, vec!["key6", "key7", "key8", "key9"]);
+
+    // an entry that can never fit is not kept either
+    store.set("big".to_owned(), "v".repeat(200))?;
+    assert_eq!(store.get("big".to_owned())?, None);
+    Ok(())
+}
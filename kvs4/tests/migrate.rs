@@ -0,0 +1,123 @@
+use kvs::{
+    digest, finish_swap, migrate, swap_dirs, swap_pending, KvStore, KvStoreFollower, KvsEngine,
+    MemStore, Result, SledStore,
+};
+use std::fs;
+use tempfile::TempDir;
+
+#[test]
+fn migrate_between_engines() -> Result<()> {
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(kvs_dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key7".to_owned())?;
+    let source = digest(&store)?;
+    assert_eq!(source.keys, 999);
+
+    let db = SledStore::new(sled::open(sled_dir.path())?);
+    assert_eq!(migrate(&store, &db)?, source);
+    assert_eq!(db.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(db.get("key7".to_owned())?, None);
+
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(kvs_dir.path())?;
+    assert_eq!(migrate(&db, &store)?, source);
+    Ok(())
+}
+
+// Digests are the same in every build, so they can be compared across releases.
+#[test]
+fn digest_is_stable() -> Result<()> {
+    let store = MemStore::new();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(digest(&store)?.checksum, 0xad19_d86c_1870_34f2);
+
+    // where the key ends is part of the checksum
+    let moved = MemStore::new();
+    moved.set("key1v".to_owned(), "alue1".to_owned())?;
+    moved.set("key2".to_owned(), "value2".to_owned())?;
+    assert_ne!(digest(&moved)?, digest(&store)?);
+    Ok(())
+}
+
+// A target that already holds other data does not match the source.
+#[test]
+fn migrate_detects_mismatch() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = KvStore::open(source_dir.path())?;
+    source.set("key1".to_owned(), "value1".to_owned())?;
+    let target = KvStore::open(target_dir.path())?;
+    target.set("stale".to_owned(), "value".to_owned())?;
+
+    assert!(migrate(&source, &target).is_err());
+    Ok(())
+}
+
+// A follower is enough to read the source.
+#[test]
+fn migrate_from_follower() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(source_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let source = digest(&store)?;
+    drop(store);
+
+    let target = KvStore::open(target_dir.path())?;
+    assert_eq!(
+        migrate(&KvStoreFollower::open(source_dir.path())?, &target)?,
+        source
+    );
+    assert_eq!(target.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn swap_dirs_replaces_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().join("data");
+    let staging = temp_dir.path().join("data.staging");
+    let backup = temp_dir.path().join("data.backup");
+    fs::create_dir(&dir)?;
+    fs::write(dir.join("engine"), "kvs")?;
+    fs::create_dir(&staging)?;
+    fs::write(staging.join("engine"), "sled")?;
+
+    swap_dirs(&dir, &staging, &backup)?;
+    assert_eq!(fs::read_to_string(dir.join("engine"))?, "sled");
+    assert_eq!(fs::read_to_string(backup.join("engine"))?, "kvs");
+    assert!(!staging.exists());
+    assert!(!swap_pending(&dir));
+    assert!(!finish_swap(&dir)?);
+    Ok(())
+}
+
+// A crash between the two renames of `swap_dirs` leaves the data directory
+// missing, and `finish_swap` puts the staged copy in its place.
+#[test]
+fn finish_interrupted_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().join("data");
+    let staging = temp_dir.path().join("data.staging");
+    let backup = temp_dir.path().join("data.backup");
+    fs::create_dir(&backup)?;
+    fs::write(backup.join("engine"), "kvs")?;
+    fs::create_dir(&staging)?;
+    fs::write(staging.join("engine"), "sled")?;
+    fs::write(
+        temp_dir.path().join("data.swap"),
+        format!("{}\n{}\n", staging.display(), backup.display()),
+    )?;
+
+    assert!(swap_pending(&dir));
+    assert!(finish_swap(&dir)?);
+    assert_eq!(fs::read_to_string(dir.join("engine"))?, "sled");
+    assert_eq!(fs::read_to_string(backup.join("engine"))?, "kvs");
+    assert!(!swap_pending(&dir));
+    Ok(())
+}
@@ -0,0 +1,59 @@
+// The file size limit applies to the whole process, so this test has a binary of
+// its own.
+
+use kvs::{Health, KvStore, KvsEngine, KvsError, Result};
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// A little more than `RETRY_INTERVAL` of `KvStore`.
+const RETRY_DELAY: Duration = Duration::from_secs(6);
+
+/// Makes writes that would grow a file beyond `limit` bytes fail, the way they do
+/// on a full disk.
+fn limit_file_size(limit: libc::rlim_t) {
+    let rlimit = libc::rlimit {
+        rlim_cur: limit,
+        rlim_max: libc::RLIM_INFINITY,
+    };
+    unsafe {
+        // fail with `EFBIG` instead of killing the process
+        libc::signal(libc::SIGXFSZ, libc::SIG_IGN);
+        assert_eq!(libc::setrlimit(libc::RLIMIT_FSIZE, &rlimit), 0);
+    }
+}
+
+// A failed write is rolled back and turns the store read-only. Reads are still
+// served, and writes are let through again once the fault clears.
+#[test]
+fn kvs_read_only_on_write_failure() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let log = temp_dir.path().join("1.log");
+    let good_len = fs::metadata(&log)?.len();
+
+    // room for a part of the next record only
+    limit_file_size(good_len + 10);
+    let res = store.set("key2".to_owned(), "value2".repeat(10));
+    assert!(matches!(res, Err(KvsError::ReadOnly(_))));
+    assert_eq!(fs::metadata(&log)?.len(), good_len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(matches!(store.stats()?.health, Health::ReadOnly(_)));
+
+    // the fault has cleared, but writes are only tried again after a while
+    limit_file_size(libc::RLIM_INFINITY);
+    let res = store.set("key2".to_owned(), "value2".to_owned());
+    assert!(matches!(res, Err(KvsError::ReadOnly(_))));
+    thread::sleep(RETRY_DELAY);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.stats()?.health, Health::Ok);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
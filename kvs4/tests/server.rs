@@ -0,0 +1,305 @@
+use kvs::{
+    ElasticThreadPool, KvStore, KvsClient, KvsEngine, KvsError, KvsServer, NaiveThreadPool,
+    RayonThreadPool, Result, SharedQueueThreadPool, ThreadPool, WorkStealingThreadPool,
+};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Connections are served at the same time, so an idle client does not hold up
+// the others.
+fn serve_clients_concurrently<P: ThreadPool>(addr: &str) -> Result<()> {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = P::new(4)?;
+    thread::spawn(move || KvsServer::new(store, pool).run(addr));
+    thread::sleep(Duration::from_secs(1));
+
+    let mut idle = KvsClient::connect(addr)?;
+    idle.set("idle".to_owned(), "connected".to_owned())?;
+
+    let handles: Vec<_> = (0..3)
+        .map(|i| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect(addr)?;
+                for j in 0..100 {
+                    client.set(format!("key{}-{}", i, j), format!("value{}", j))?;
+                }
+                assert_eq!(client.get("idle".to_owned())?, Some("connected".to_owned()));
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    for i in 0..3 {
+        assert_eq!(
+            idle.get(format!("key{}-99", i))?,
+            Some("value99".to_owned())
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn naive_pool_serves_clients_concurrently() -> Result<()> {
+    serve_clients_concurrently::<NaiveThreadPool>("127.0.0.1:4010")
+}
+
+#[test]
+fn shared_queue_pool_serves_clients_concurrently() -> Result<()> {
+    serve_clients_concurrently::<SharedQueueThreadPool>("127.0.0.1:4011")
+}
+
+#[test]
+fn rayon_pool_serves_clients_concurrently() -> Result<()> {
+    serve_clients_concurrently::<RayonThreadPool>("127.0.0.1:4012")
+}
+
+#[test]
+fn elastic_pool_serves_clients_concurrently() -> Result<()> {
+    serve_clients_concurrently::<ElasticThreadPool>("127.0.0.1:4014")
+}
+
+#[test]
+fn work_stealing_pool_serves_clients_concurrently() -> Result<()> {
+    serve_clients_concurrently::<WorkStealingThreadPool>("127.0.0.1:4015")
+}
+
+// Shutdown does not wait for clients that are between requests.
+#[test]
+fn shutdown_closes_idle_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvsServer::new(store, SharedQueueThreadPool::new(2)?)
+        .start("127.0.0.1:0".parse().unwrap())?;
+    let addr = handle.local_addr();
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+
+    let started = Instant::now();
+    handle.shutdown(Duration::from_secs(10))?;
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(client.get("key".to_owned()).is_err());
+    assert!(KvsClient::connect(addr).is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Other writes go ahead while a streamed value is still being received.
+#[test]
+fn set_stream_does_not_block_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvsServer::new(store, SharedQueueThreadPool::new(2)?)
+        .start("127.0.0.1:0".parse().unwrap())?;
+    let mut tcp = TcpStream::connect(handle.local_addr())?;
+    tcp.write_all(br#"{"SetStream":{"key":"slow","len":10}}{"Chunk":"hello"}"#)?;
+    thread::sleep(Duration::from_millis(500));
+
+    let started = Instant::now();
+    KvsClient::connect(handle.local_addr())?.set("fast".to_owned(), "value".to_owned())?;
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    tcp.write_all(br#"{"Chunk":"world"}"#)?;
+    let mut response = [0; 11];
+    tcp.read_exact(&mut response)?;
+    assert_eq!(&response, br#"{"Ok":null}"#);
+    drop(tcp);
+    handle.shutdown(Duration::from_secs(10))?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("slow".to_owned())?, Some("helloworld".to_owned()));
+    assert_eq!(store.get("fast".to_owned())?, Some("value".to_owned()));
+    // the value was spooled next to the log, and the spool is gone
+    for entry in temp_dir.path().read_dir()? {
+        assert_ne!(entry?.path().extension(), Some("spool".as_ref()));
+    }
+    Ok(())
+}
+
+// A chunk beyond the announced length fails the value and ends the connection.
+#[test]
+fn set_stream_rejects_extra_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvsServer::new(store, SharedQueueThreadPool::new(2)?)
+        .start("127.0.0.1:0".parse().unwrap())?;
+    let mut tcp = TcpStream::connect(handle.local_addr())?;
+    tcp.write_all(br#"{"SetStream":{"key":"key","len":5}}{"Chunk":"helloworld"}"#)?;
+    tcp.write_all(br#"{"Get":{"key":"key"}}"#)?;
+    let mut response = String::new();
+    tcp.read_to_string(&mut response)?;
+    assert!(response.starts_with(r#"{"Err":"#), "{}", response);
+    assert!(response.contains("exceeds"), "{}", response);
+    handle.shutdown(Duration::from_secs(10))?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, None);
+    Ok(())
+}
+
+// A request that has started when the server stops is still answered.
+#[test]
+fn shutdown_finishes_requests_in_flight() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvsServer::new(store, SharedQueueThreadPool::new(2)?)
+        .start("127.0.0.1:0".parse().unwrap())?;
+    let mut tcp = TcpStream::connect(handle.local_addr())?;
+    tcp.write_all(br#"{"SetStream":{"key":"key","len":10}}{"Chunk":"hello"}"#)?;
+    thread::sleep(Duration::from_millis(500));
+
+    let shutdown = thread::spawn(move || handle.shutdown(Duration::from_secs(10)));
+    thread::sleep(Duration::from_millis(500));
+    tcp.write_all(br#"{"Chunk":"world"}"#)?;
+    let mut response = String::new();
+    tcp.read_to_string(&mut response)?;
+    assert_eq!(response, r#"{"Ok":null}"#);
+    shutdown.join().unwrap()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("helloworld".to_owned()));
+    Ok(())
+}
+
+// A request that does not finish in time is cut off at the deadline.
+#[test]
+fn shutdown_cuts_off_at_deadline() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvsServer::new(store, SharedQueueThreadPool::new(2)?)
+        .start("127.0.0.1:0".parse().unwrap())?;
+    let mut tcp = TcpStream::connect(handle.local_addr())?;
+    tcp.write_all(br#"{"SetStream":{"key":"key","len":10}}{"Chunk":"hello"}"#)?;
+    thread::sleep(Duration::from_millis(500));
+
+    let started = Instant::now();
+    handle.shutdown(Duration::from_secs(1))?;
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_secs(1) && elapsed < Duration::from_secs(5));
+    let mut response = String::new();
+    tcp.read_to_string(&mut response)?;
+    assert_eq!(response, "");
+    Ok(())
+}
+
+// A watch that sees no events still ends when the server stops.
+#[test]
+fn shutdown_ends_quiet_watches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvsServer::new(store, SharedQueueThreadPool::new(2)?)
+        .start("127.0.0.1:0".parse().unwrap())?;
+    let mut watch = KvsClient::connect(handle.local_addr())?.watch("quiet".to_owned())?;
+
+    let started = Instant::now();
+    handle.shutdown(Duration::from_secs(10))?;
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(watch.next().is_none());
+    Ok(())
+}
+
+// Watches beyond the limit are told the server is busy, and a slot is free again
+// once a watching client goes away.
+#[test]
+fn reject_watches_beyond_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvsServer::new(store, SharedQueueThreadPool::new(2)?)
+        .max_watches(1)
+        .start("127.0.0.1:0".parse().unwrap())?;
+    let watch = KvsClient::connect(handle.local_addr())?.watch("key".to_owned())?;
+    match KvsClient::connect(handle.local_addr())?.watch("key".to_owned()) {
+        Err(KvsError::StringErr(msg)) => assert_eq!(msg, "Server busy"),
+        res => panic!("unexpected result {:?}", res.map(|_| ())),
+    }
+
+    drop(watch);
+    let started = Instant::now();
+    loop {
+        match KvsClient::connect(handle.local_addr())?.watch("key".to_owned()) {
+            Ok(_) => break,
+            Err(_) if started.elapsed() < Duration::from_secs(5) => {
+                thread::sleep(Duration::from_millis(50))
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    handle.shutdown(Duration::from_secs(10))?;
+    Ok(())
+}
+
+// Errors of the engine reach the client as the same kinds.
+#[test]
+fn client_errors_keep_their_kind() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvsServer::new(store, SharedQueueThreadPool::new(2)?)
+        .start("127.0.0.1:0".parse().unwrap())?;
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    client.set("name".to_owned(), "alice".to_owned())?;
+    client.set("max".to_owned(), i64::MAX.to_string())?;
+
+    match client.incr("name".to_owned(), 1) {
+        Err(KvsError::NotAnInteger(key)) => assert_eq!(key, "name"),
+        res => panic!("unexpected result {:?}", res),
+    }
+    assert!(matches!(
+        client.incr("max".to_owned(), 1),
+        Err(KvsError::IntegerOverflow)
+    ));
+    assert!(matches!(
+        client.remove("missing".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    handle.shutdown(Duration::from_secs(10))?;
+    Ok(())
+}
+
+// Clients beyond the workers and the queue are told the server is busy.
+#[test]
+fn reject_clients_when_busy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvsServer::new(store, SharedQueueThreadPool::with_queue_capacity(1, 1)?)
+        .start("127.0.0.1:0".parse().unwrap())?;
+    let addr = handle.local_addr();
+
+    // the only worker serves the first client as long as it is connected
+    let mut first = KvsClient::connect(addr)?;
+    first.set("key".to_owned(), "value".to_owned())?;
+    let mut queued = KvsClient::connect(addr)?;
+    thread::sleep(Duration::from_millis(200));
+    let mut rejected = KvsClient::connect(addr)?;
+    match rejected.set("key".to_owned(), "other".to_owned()) {
+        Err(KvsError::StringErr(msg)) => assert_eq!(msg, "Server busy"),
+        res => panic!("unexpected result {:?}", res),
+    }
+    let mut rejected = KvsClient::connect(addr)?;
+    match rejected.pool_stats() {
+        Err(KvsError::StringErr(msg)) => assert_eq!(msg, "Server busy"),
+        res => panic!("unexpected result {:?}", res),
+    }
+    let stats = first.pool_stats()?;
+    assert_eq!(stats.active_workers, 1);
+    assert_eq!(stats.idle_workers, 0);
+    assert_eq!(stats.queue_depth, 1);
+
+    drop(first);
+    assert_eq!(queued.get("key".to_owned())?, Some("value".to_owned()));
+    // the worker only takes the next job once the last one has ended
+    let stats = queued.pool_stats()?;
+    assert_eq!(stats.active_workers, 1);
+    assert_eq!(stats.queue_depth, 0);
+    assert_eq!(stats.jobs_completed, 1);
+    assert_eq!(stats.queue_wait.total(), 2);
+    handle.shutdown(Duration::from_secs(1))
+}
@@ -0,0 +1,101 @@
+use kvs::{KvStore, KvsEngine, Result};
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use tempfile::TempDir;
+
+// After a clean shutdown, reopening loads the index snapshot instead of replaying
+// the log. A damaged record that only a replay reads shows which one happened.
+#[test]
+fn open_from_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("index.snapshot").exists());
+
+    // the first record is stale, so no read ever needs it
+    let mut log = OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("1.log"))?;
+    log.write_all(b"xxxx")?;
+    drop(log);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    fs::remove_file(temp_dir.path().join("index.snapshot"))?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    Ok(())
+}
+
+// Writes after the snapshot are replayed, even if the store is not shut down cleanly.
+#[test]
+fn replay_tail_after_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    // crash: the writer never saves a snapshot
+    std::mem::forget(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// A snapshot of generations a compaction removed since is not used.
+#[test]
+fn discard_outdated_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot_path = temp_dir.path().join("index.snapshot");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    let outdated = fs::read(&snapshot_path)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..50000 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+    }
+    std::mem::forget(store);
+    assert!(!temp_dir.path().join("1.log").exists());
+    fs::write(&snapshot_path, outdated)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("49999".to_owned()));
+    Ok(())
+}
+
+// A damaged snapshot is ignored and the log is replayed instead.
+#[test]
+fn ignore_damaged_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let mut snapshot = OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("index.snapshot"))?;
+    snapshot.seek(SeekFrom::Start(20))?;
+    snapshot.write_all(b"9")?;
+    drop(snapshot);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use kvs::{
+    KvStore, KvsClient, KvsEngine, KvsError, KvsServer, MemStore, Result, SharedQueueThreadPool,
+    ThreadPool,
+};
+use std::io::{self, Read};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn read_all<R: Read>(mut reader: R) -> String {
+    let mut value = String::new();
+    reader.read_to_string(&mut value).unwrap();
+    value
+}
+
+fn tricky_values() -> Vec<String> {
+    vec![
+        String::new(),
+        "plain".to_owned(),
+        "\"quoted\" \\back\\slashed\\".to_owned(),
+        "tab\tnew\nline\r\u{0}\u{1f}\u{7f}".to_owned(),
+        "ünïcödé 漢字 🦀🦀".to_owned(),
+        "🦀\\\"".repeat(100_000),
+    ]
+}
+
+// A value written from a reader reads back the same, both as a whole and as a
+// stream, whatever has to be escaped in the log.
+#[test]
+fn stream_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for (i, value) in tricky_values().into_iter().enumerate() {
+        let key = format!("key{}", i);
+        store.set_from_reader(key.clone(), value.len() as u64, value.as_bytes())?;
+        assert_eq!(store.get(key.clone())?, Some(value.clone()));
+        assert_eq!(read_all(store.get_reader(key)?.unwrap()), value);
+    }
+
+    // values written by `set` stream the same way
+    for (i, value) in tricky_values().into_iter().enumerate() {
+        let key = format!("set{}", i);
+        store.set(key.clone(), value.clone())?;
+        assert_eq!(read_all(store.get_reader(key)?.unwrap()), value);
+    }
+    assert!(store.get_reader("missing".to_owned())?.is_none());
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for (i, value) in tricky_values().into_iter().enumerate() {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value));
+    }
+    Ok(())
+}
+
+// Only the first `len` bytes of the reader are stored.
+#[test]
+fn stream_stops_at_len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_from_reader("key".to_owned(), 5, "value and more".as_bytes())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Bad input fails the write without touching the store, which keeps taking writes.
+#[test]
+fn reject_invalid_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+
+    let invalid_utf8: &[u8] = b"abc\xff\xfe";
+    match store.set_from_reader("key".to_owned(), 5, invalid_utf8) {
+        Err(KvsError::InvalidValue(_)) => {}
+        res => panic!("unexpected result {:?}", res.map(|_| ())),
+    }
+    match store.set_from_reader("key".to_owned(), 10, "short".as_bytes()) {
+        Err(KvsError::InvalidValue(_)) => {}
+        res => panic!("unexpected result {:?}", res.map(|_| ())),
+    }
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Engines without streaming support fall back to whole values.
+#[test]
+fn mem_store_streams() -> Result<()> {
+    let store = MemStore::new();
+    let value = "🦀".repeat(1000);
+    store.set_from_reader("key".to_owned(), value.len() as u64, value.as_bytes())?;
+    assert_eq!(
+        read_all(store.get_reader("key".to_owned())?.unwrap()),
+        value
+    );
+    assert!(store.get_reader("missing".to_owned())?.is_none());
+    assert!(store
+        .set_from_reader("key".to_owned(), 10, "short".as_bytes())
+        .is_err());
+    Ok(())
+}
+
+// Values larger than a chunk cross the network in pieces and the connection stays
+// usable afterwards, even when a stream is dropped half read.
+#[test]
+fn client_streams() -> Result<()> {
+    let addr = "127.0.0.1:4009".parse().unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(2)?;
+    thread::spawn(move || KvsServer::new(store, pool).run(addr));
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr)?;
+    let value = "é\"\\x".repeat(100_000);
+    client.set_from_reader("big".to_owned(), value.len() as u64, value.as_bytes())?;
+    assert_eq!(client.get("big".to_owned())?, Some(value.clone()));
+    assert_eq!(
+        read_all(client.get_reader("big".to_owned())?.unwrap()),
+        value
+    );
+
+    let mut stream = client.get_reader("big".to_owned())?.unwrap();
+    let mut start = [0; 10];
+    stream.read_exact(&mut start)?;
+    drop(stream);
+    assert!(client.get_reader("missing".to_owned())?.is_none());
+    client.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(
+        read_all(client.get_reader("small".to_owned())?.unwrap()),
+        "value"
+    );
+
+    let empty = io::empty();
+    client.set_from_reader("empty".to_owned(), 0, empty)?;
+    assert_eq!(client.get("empty".to_owned())?, Some(String::new()));
+    Ok(())
+}
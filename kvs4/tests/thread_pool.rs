@@ -1,9 +1,15 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use kvs::Result;
-use kvs::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    ElasticOptions, ElasticThreadPool, NaiveThreadPool, PoolStats, RayonThreadPool,
+    SharedQueueThreadPool, ThreadPool, WorkStealingThreadPool,
+};
 
+use crossbeam::channel;
 use crossbeam_utils::sync::WaitGroup;
 
 fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
@@ -46,6 +52,188 @@ fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
     spawn_counter(pool)
 }
 
+// `join` returns once every job has run, including jobs that panic.
+fn join_waits_for_jobs<P: ThreadPool>() -> Result<()> {
+    const TASK_NUM: usize = 20;
+
+    let pool = P::new(2)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    for i in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            counter.fetch_add(1, Ordering::SeqCst);
+            if i % 5 == 0 {
+                panic_control::disable_hook_in_current_thread();
+                panic!();
+            }
+        })
+    }
+    pool.join();
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+    Ok(())
+}
+
+// Jobs spawned after `shutdown` never run.
+fn spawn_after_shutdown<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(2)?;
+    let ran = Arc::new(AtomicBool::new(false));
+    pool.shutdown();
+    {
+        let ran = Arc::clone(&ran);
+        pool.spawn(move || ran.store(true, Ordering::SeqCst));
+    }
+    pool.join();
+    assert!(!ran.load(Ordering::SeqCst));
+    Ok(())
+}
+
+// Dropping a pool does not wait for its jobs, but they still run.
+fn drop_drains_jobs<P: ThreadPool>() -> Result<()> {
+    const TASK_NUM: usize = 10;
+
+    let pool = P::new(1)?;
+    let wg = WaitGroup::new();
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        let wg = wg.clone();
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            counter.fetch_add(1, Ordering::SeqCst);
+            drop(wg);
+        })
+    }
+    drop(pool);
+    assert!(counter.load(Ordering::SeqCst) < TASK_NUM);
+    wg.wait();
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+    Ok(())
+}
+
+// Handles yield what jobs return, or how they panicked.
+fn spawn_with_handle<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(2)?;
+    let handles: Vec<_> = (0..10)
+        .map(|i| pool.spawn_with_handle(move || i * 2))
+        .collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.join(), Ok(i * 2));
+    }
+
+    let handle = pool.spawn_with_handle(|| -> usize {
+        panic_control::disable_hook_in_current_thread();
+        panic!("boom");
+    });
+    assert_eq!(handle.join().unwrap_err().message(), "boom");
+
+    // the pool still runs jobs after the panic
+    let (sender, receiver) = mpsc::channel::<()>();
+    let mut handle = pool.spawn_with_handle(move || receiver.recv().is_ok());
+    for _ in 0..10 {
+        handle = handle.try_join().expect_err("the job is still blocked");
+    }
+    sender.send(()).unwrap();
+    assert_eq!(handle.join(), Ok(true));
+
+    pool.shutdown();
+    assert!(pool.spawn_with_handle(|| ()).join().is_err());
+    Ok(())
+}
+
+// A full queue hands jobs back to `try_spawn` and holds up `spawn`.
+fn bounded_queue<P: ThreadPool + Sync>() -> Result<()> {
+    let pool = P::with_queue_capacity(1, 1)?;
+    let (sender, receiver) = channel::unbounded::<()>();
+    let done = Arc::new(AtomicUsize::new(0));
+    let job = || {
+        let receiver = receiver.clone();
+        let done = Arc::clone(&done);
+        move || {
+            receiver.recv().unwrap();
+            done.fetch_add(1, Ordering::SeqCst);
+        }
+    };
+
+    // a worker and the queue take at most two blocked jobs
+    let mut accepted = 0;
+    let rejected = loop {
+        match pool.try_spawn(job()) {
+            Ok(()) => accepted += 1,
+            Err(job) => break job,
+        }
+        assert!(accepted <= 2);
+        // let the worker take the job off the queue
+        thread::sleep(Duration::from_millis(100));
+    };
+
+    thread::scope(|scope| {
+        let blocked = scope.spawn(|| pool.spawn(job()));
+        thread::sleep(Duration::from_millis(200));
+        assert!(!blocked.is_finished());
+        for _ in 0..=accepted {
+            sender.send(()).unwrap();
+        }
+        blocked.join().unwrap();
+    });
+    sender.send(()).unwrap();
+    rejected();
+    pool.join();
+    assert_eq!(done.load(Ordering::SeqCst), accepted + 2);
+    Ok(())
+}
+
+/// Polls the stats of `pool` until `done` holds, for at most a few seconds.
+fn wait_for_stats<P: ThreadPool>(pool: &P, done: impl Fn(&PoolStats) -> bool) -> PoolStats {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let stats = pool.stats();
+        if done(&stats) {
+            return stats;
+        }
+        assert!(Instant::now() < deadline, "unexpected stats {:?}", stats);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// The stats follow jobs from the queue to the workers, and count how they end.
+// `running` of four blocked jobs run at once, on a pool with `idle` workers when
+// it has no job.
+fn stats<P: ThreadPool>(running: usize, idle: usize) -> Result<()> {
+    let pool = P::new(2)?;
+    let (sender, receiver) = channel::unbounded::<()>();
+    for _ in 0..4 {
+        let receiver = receiver.clone();
+        pool.spawn(move || receiver.recv().unwrap());
+    }
+    let stats = wait_for_stats(&pool, |stats| stats.active_workers == running);
+    assert_eq!(stats.idle_workers, 0);
+    assert_eq!(stats.queue_depth, 4 - running);
+    assert_eq!(stats.jobs_completed, 0);
+
+    for _ in 0..4 {
+        sender.send(()).unwrap();
+    }
+    let stats = wait_for_stats(&pool, |stats| {
+        stats.jobs_completed == 4 && stats.idle_workers == idle
+    });
+    assert_eq!(stats.active_workers, 0);
+    assert_eq!(stats.queue_depth, 0);
+    assert_eq!(stats.queue_wait.total(), 4);
+
+    pool.spawn(|| {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    });
+    let stats = wait_for_stats(&pool, |stats| {
+        stats.jobs_panicked == 1 && stats.idle_workers == idle
+    });
+    assert_eq!(stats.jobs_completed, 4);
+    assert_eq!(stats.queue_wait.total(), 5);
+    pool.join();
+    Ok(())
+}
+
 #[test]
 fn naive_thread_pool_spawn_counter() -> Result<()> {
     let pool = NaiveThreadPool::new(4)?;
@@ -68,3 +256,305 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<RayonThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_join() -> Result<()> {
+    join_waits_for_jobs::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_join() -> Result<()> {
+    join_waits_for_jobs::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_join() -> Result<()> {
+    join_waits_for_jobs::<RayonThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_spawn_after_shutdown() -> Result<()> {
+    spawn_after_shutdown::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_after_shutdown() -> Result<()> {
+    spawn_after_shutdown::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_spawn_after_shutdown() -> Result<()> {
+    spawn_after_shutdown::<RayonThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_drop() -> Result<()> {
+    drop_drains_jobs::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_drop() -> Result<()> {
+    drop_drains_jobs::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_drop() -> Result<()> {
+    drop_drains_jobs::<RayonThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle::<RayonThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_bounded_queue() -> Result<()> {
+    bounded_queue::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_bounded_queue() -> Result<()> {
+    bounded_queue::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_bounded_queue() -> Result<()> {
+    bounded_queue::<RayonThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_stats() -> Result<()> {
+    stats::<NaiveThreadPool>(4, 0)
+}
+
+#[test]
+fn shared_queue_thread_pool_stats() -> Result<()> {
+    stats::<SharedQueueThreadPool>(2, 2)
+}
+
+#[test]
+fn shared_queue_thread_pool_invalid_size() {
+    assert!(SharedQueueThreadPool::new(0).is_err());
+    assert!(SharedQueueThreadPool::with_queue_capacity(0, 4).is_err());
+}
+
+#[test]
+fn rayon_thread_pool_stats() -> Result<()> {
+    stats::<RayonThreadPool>(2, 2)
+}
+
+#[test]
+fn elastic_thread_pool_spawn_counter() -> Result<()> {
+    let pool = ElasticThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn elastic_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<ElasticThreadPool>()
+}
+
+#[test]
+fn elastic_thread_pool_join() -> Result<()> {
+    join_waits_for_jobs::<ElasticThreadPool>()
+}
+
+#[test]
+fn elastic_thread_pool_spawn_after_shutdown() -> Result<()> {
+    spawn_after_shutdown::<ElasticThreadPool>()
+}
+
+#[test]
+fn elastic_thread_pool_drop() -> Result<()> {
+    drop_drains_jobs::<ElasticThreadPool>()
+}
+
+#[test]
+fn elastic_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle::<ElasticThreadPool>()
+}
+
+#[test]
+fn elastic_thread_pool_bounded_queue() -> Result<()> {
+    bounded_queue::<ElasticThreadPool>()
+}
+
+#[test]
+fn elastic_thread_pool_stats() -> Result<()> {
+    stats::<ElasticThreadPool>(2, 2)
+}
+
+// Workers are added for jobs that wait past the threshold, up to the maximum, and
+// the ones beyond the minimum exit once idle for the keep-alive.
+#[test]
+fn elastic_thread_pool_scaling() -> Result<()> {
+    let pool = ElasticThreadPool::with_options(ElasticOptions {
+        min_workers: 1,
+        max_workers: 3,
+        wait_threshold: Duration::from_millis(300),
+        keep_alive: Duration::from_millis(500),
+        queue_capacity: None,
+    })?;
+    let (sender, receiver) = channel::unbounded::<()>();
+    let job = || {
+        let receiver = receiver.clone();
+        move || receiver.recv().unwrap()
+    };
+
+    // the second job waits for the threshold before a worker is added for it
+    pool.spawn(job());
+    pool.spawn(job());
+    thread::sleep(Duration::from_millis(100));
+    let stats = pool.stats();
+    assert_eq!((stats.active_workers, stats.queue_depth), (1, 1));
+    wait_for_stats(&pool, |stats| stats.active_workers == 2);
+
+    // no more than the maximum
+    for _ in 0..3 {
+        pool.spawn(job());
+    }
+    wait_for_stats(&pool, |stats| stats.active_workers == 3);
+    thread::sleep(Duration::from_millis(700));
+    let stats = pool.stats();
+    assert_eq!((stats.active_workers, stats.queue_depth), (3, 2));
+
+    for _ in 0..5 {
+        sender.send(()).unwrap();
+    }
+    let stats = wait_for_stats(&pool, |stats| stats.jobs_completed == 5);
+    assert_eq!(stats.idle_workers, 3);
+    wait_for_stats(&pool, |stats| stats.idle_workers == 1);
+
+    // a single job at a time runs on the remaining worker
+    for _ in 0..3 {
+        pool.spawn_with_handle(|| ()).join().unwrap();
+    }
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(pool.stats().idle_workers, 1);
+    pool.join();
+    Ok(())
+}
+
+#[test]
+fn elastic_thread_pool_invalid_size() {
+    let options = ElasticOptions {
+        min_workers: 4,
+        max_workers: 2,
+        ..ElasticOptions::default()
+    };
+    assert!(ElasticThreadPool::with_options(options).is_err());
+    assert!(ElasticThreadPool::new(0).is_err());
+}
+
+#[test]
+fn work_stealing_thread_pool_spawn_counter() -> Result<()> {
+    let pool = WorkStealingThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn work_stealing_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_join() -> Result<()> {
+    join_waits_for_jobs::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_spawn_after_shutdown() -> Result<()> {
+    spawn_after_shutdown::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_drop() -> Result<()> {
+    drop_drains_jobs::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_bounded_queue() -> Result<()> {
+    bounded_queue::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_stats() -> Result<()> {
+    stats::<WorkStealingThreadPool>(2, 2)
+}
+
+// A job spawned by a job goes to the deque of its worker, ahead of the jobs spawned
+// from outside in the meantime.
+#[test]
+fn work_stealing_thread_pool_keeps_nested_jobs_local() -> Result<()> {
+    let pool = Arc::new(WorkStealingThreadPool::new(1)?);
+    let (order_sender, order) = mpsc::channel();
+    let (spawned_sender, spawned) = mpsc::channel();
+    let (finish_sender, finish) = mpsc::channel::<()>();
+    {
+        let inner = Arc::clone(&pool);
+        let order_sender = order_sender.clone();
+        pool.spawn(move || {
+            inner.spawn(move || order_sender.send("nested").unwrap());
+            spawned_sender.send(()).unwrap();
+            finish.recv().unwrap();
+        });
+    }
+    spawned.recv().unwrap();
+    pool.spawn(move || order_sender.send("outside").unwrap());
+    finish_sender.send(()).unwrap();
+
+    assert_eq!(order.recv().unwrap(), "nested");
+    assert_eq!(order.recv().unwrap(), "outside");
+    Ok(())
+}
+
+// Jobs fanning out into more jobs keep every worker busy until the tree is done.
+#[test]
+fn work_stealing_thread_pool_nested_fan_out() -> Result<()> {
+    fn fan_out(
+        pool: Arc<WorkStealingThreadPool>,
+        depth: u32,
+        wg: WaitGroup,
+        count: Arc<AtomicUsize>,
+    ) {
+        count.fetch_add(1, Ordering::SeqCst);
+        if depth == 0 {
+            return;
+        }
+        for _ in 0..2 {
+            let (next, wg, count) = (Arc::clone(&pool), wg.clone(), Arc::clone(&count));
+            pool.spawn(move || fan_out(next, depth - 1, wg, count));
+        }
+    }
+
+    let pool = Arc::new(WorkStealingThreadPool::new(4)?);
+    let wg = WaitGroup::new();
+    let count = Arc::new(AtomicUsize::new(0));
+    {
+        let (next, wg, count) = (Arc::clone(&pool), wg.clone(), Arc::clone(&count));
+        pool.spawn(move || fan_out(next, 10, wg, count));
+    }
+    wg.wait();
+    assert_eq!(count.load(Ordering::SeqCst), (1 << 11) - 1);
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use kvs::{
+    KvStore, KvStoreFollower, KvsClient, KvsEngine, KvsError, KvsServer, LsmStore, MemStore,
+    Result, SharedQueueThreadPool, SledStore, ThreadPool,
+};
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const SHORT_TTL: Duration = Duration::from_millis(100);
+const LONG_TTL: Duration = Duration::from_secs(3600);
+
+fn keys<E: KvsEngine>(engine: &E) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    engine.scan(|key, _| {
+        keys.push(key);
+        Ok(())
+    })?;
+    Ok(keys)
+}
+
+// Expired keys read as missing everywhere, and count as zero for `incr_by`.
+fn expiry<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set_with_ttl("short".to_owned(), "value1".to_owned(), SHORT_TTL)?;
+    engine.set_with_ttl("long".to_owned(), "value2".to_owned(), LONG_TTL)?;
+    engine.set_with_ttl("counter".to_owned(), "41".to_owned(), SHORT_TTL)?;
+    engine.set("forever".to_owned(), "value3".to_owned())?;
+    assert_eq!(engine.get("short".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(keys(&engine)?, vec!["counter", "forever", "long", "short"]);
+
+    thread::sleep(2 * SHORT_TTL);
+    assert_eq!(engine.get("short".to_owned())?, None);
+    assert!(engine.get_reader("short".to_owned())?.is_none());
+    assert_eq!(engine.get("long".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(keys(&engine)?, vec!["forever", "long"]);
+    assert!(matches!(
+        engine.remove("short".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    // the new value has no TTL
+    assert_eq!(engine.incr_by("counter".to_owned(), 1)?, 1);
+    thread::sleep(2 * SHORT_TTL);
+    assert_eq!(engine.get("counter".to_owned())?, Some("1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn kvs_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    expiry(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    expiry(SledStore::new(sled::open(temp_dir.path())?))
+}
+
+#[test]
+fn mem_expiry() -> Result<()> {
+    expiry(MemStore::new())
+}
+
+#[test]
+fn lsm_ttl_unsupported() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmStore::open(temp_dir.path())?;
+    assert!(matches!(
+        store.set_with_ttl("key".to_owned(), "value".to_owned(), LONG_TTL),
+        Err(KvsError::Unsupported(_))
+    ));
+    Ok(())
+}
+
+// The expiry is part of the log record, so it survives a restart and followers
+// see it too.
+#[test]
+fn kvs_expiry_survives_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl("short".to_owned(), "value1".to_owned(), SHORT_TTL)?;
+    store.set_with_ttl("long".to_owned(), "value2".to_owned(), LONG_TTL)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let follower = KvStoreFollower::open(temp_dir.path())?;
+    let mut value = String::new();
+    store
+        .get_reader("long".to_owned())?
+        .expect("key not found")
+        .read_to_string(&mut value)?;
+    assert_eq!(value, "value2");
+
+    thread::sleep(2 * SHORT_TTL);
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(follower.get("short".to_owned())?, None);
+    assert_eq!(follower.get("long".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A TTL sent by a client reaches the engine, and so does its refusal.
+#[test]
+fn client_set_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let handle = KvsServer::new(MemStore::new(), SharedQueueThreadPool::new(2)?)
+        .start("127.0.0.1:0".parse().unwrap())?;
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    client.set_with_ttl("key".to_owned(), "value".to_owned(), SHORT_TTL)?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    thread::sleep(2 * SHORT_TTL);
+    assert_eq!(client.get("key".to_owned())?, None);
+    handle.shutdown(Duration::from_secs(10))?;
+
+    let handle = KvsServer::new(
+        LsmStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )
+    .start("127.0.0.1:0".parse().unwrap())?;
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    assert!(matches!(
+        client.set_with_ttl("key".to_owned(), "value".to_owned(), LONG_TTL),
+        Err(KvsError::Unsupported(_))
+    ));
+    handle.shutdown(Duration::from_secs(10))?;
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use kvs::{Event, KvStore, KvsEngine, LsmStore, MemStore, Result, SledStore};
+use tempfile::TempDir;
+
+fn watch_prefix<E: KvsEngine>(engine: E) -> Result<()> {
+    let mut subscription = engine.subscribe("user:".to_owned())?;
+    engine.set("user:1".to_owned(), "alice".to_owned())?;
+    engine.set("other".to_owned(), "ignored".to_owned())?;
+    engine.set("user:2".to_owned(), "bob".to_owned())?;
+    engine.remove("user:1".to_owned())?;
+
+    let events: Vec<Event> = subscription.by_ref().take(3).collect();
+    assert_eq!(events.len(), 3);
+    match &events[0] {
+        Event::Set { key, value, .. } => assert_eq!((&key[..], &value[..]), ("user:1", "alice")),
+        event => panic!("unexpected event {:?}", event),
+    }
+    match &events[1] {
+        Event::Set { key, value, .. } => assert_eq!((&key[..], &value[..]), ("user:2", "bob")),
+        event => panic!("unexpected event {:?}", event),
+    }
+    match &events[2] {
+        Event::Remove { key, .. } => assert_eq!(key, "user:1"),
+        event => panic!("unexpected event {:?}", event),
+    }
+    assert!(events[0].seq() < events[1].seq());
+    assert!(events[1].seq() < events[2].seq());
+    Ok(())
+}
+
+#[test]
+fn kvs_watch_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    watch_prefix(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_watch_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    watch_prefix(SledStore::new(sled::open(temp_dir.path())?))
+}
+
+#[test]
+fn lsm_watch_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    watch_prefix(LsmStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn mem_watch_prefix() -> Result<()> {
+    watch_prefix(MemStore::new())
+}
+
+// Subscriptions of a `KvStore` end once the store is dropped.
+#[test]
+fn kvs_subscription_ends_with_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let subscription = store.subscribe(String::new())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert_eq!(subscription.count(), 1);
+    Ok(())
+}